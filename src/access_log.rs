@@ -0,0 +1,199 @@
+//! Per-engine access log files
+//!
+//! Files are rotated by size and/or time: `access.log` is renamed to
+//! `access.log.1`, `access.log.1` to `access.log.2` and so on, files
+//! beyond `keep` are removed.
+
+use crate::logger::Format;
+use chrono::offset::Local;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_KEEP: usize = 7;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+impl Interval {
+    /// Identifier of the current period, changes when file must be rotated
+    fn period(&self) -> String {
+        let format = match self {
+            Interval::Hourly => "%Y-%m-%d %H",
+            Interval::Daily => "%Y-%m-%d",
+            Interval::Weekly => "%G-%V",
+        };
+        Local::now().format(format).to_string()
+    }
+}
+
+/// `access_log` table of engine configuration
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    /// format of records, `[log]` format by default
+    pub format: Option<Format>,
+    /// rotate when file grows over this many bytes
+    pub max_size: Option<u64>,
+    /// rotate when hour, day or week changes
+    pub rotate: Option<Interval>,
+    /// number of rotated files to keep
+    pub keep: Option<usize>,
+}
+
+struct Output {
+    file: File,
+    size: u64,
+    period: Option<String>,
+}
+
+pub struct AccessLog {
+    config: AccessLogConfig,
+    output: Mutex<Option<Output>>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> AccessLog {
+        AccessLog {
+            config: config.clone(),
+            output: Mutex::new(None),
+        }
+    }
+
+    fn open(&self) -> io::Result<Output> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        Ok(Output {
+            size: file.metadata()?.len(),
+            file,
+            period: self.config.rotate.map(|i| i.period()),
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Shift rotated files by one and move current file to `.1`
+    fn rotate(&self) -> io::Result<()> {
+        let keep = self.config.keep.unwrap_or(DEFAULT_KEEP);
+        fs::remove_file(self.rotated_path(keep.max(1))).ok();
+        for n in (1..keep).rev() {
+            fs::rename(self.rotated_path(n), self.rotated_path(n + 1)).ok();
+        }
+        if keep > 0 {
+            fs::rename(&self.config.path, self.rotated_path(1))
+        } else {
+            fs::remove_file(&self.config.path)
+        }
+    }
+
+    fn needs_rotation(&self, output: &Output, len: u64) -> bool {
+        let too_big = self
+            .config
+            .max_size
+            .is_some_and(|max| output.size > 0 && output.size + len > max);
+        let expired = match (self.config.rotate, &output.period) {
+            (Some(interval), Some(period)) => interval.period() != *period,
+            _ => false,
+        };
+        too_big || expired
+    }
+
+    fn write_line(&self, output: &mut Option<Output>, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if let Some(current) = output {
+            if self.needs_rotation(current, len) {
+                *output = None;
+                self.rotate()?;
+            }
+        }
+        if output.is_none() {
+            *output = Some(self.open()?);
+        }
+        let current = output.as_mut().unwrap();
+        writeln!(current.file, "{}", line)?;
+        current.size += len;
+        Ok(())
+    }
+
+    pub fn write(&self, line: &str) {
+        let mut output = self.output.lock().unwrap();
+        if let Err(e) = self.write_line(&mut output, line) {
+            *output = None;
+            eprintln!("{}: {}", self.config.path.display(), e);
+        }
+    }
+
+    pub fn format(&self) -> Option<Format> {
+        self.config.format
+    }
+
+    /// Close the file, it is opened again by the next write
+    pub fn reopen(&self) {
+        *self.output.lock().unwrap() = None;
+    }
+}
+
+/// Access logs of engines keyed by `type.name`
+static LOGS: Mutex<BTreeMap<String, Arc<AccessLog>>> = Mutex::new(BTreeMap::new());
+
+/// Set access log of engine `key`, `None` sends its records to stdout.
+///
+/// Logs are kept after engine is stopped, so sessions that are still
+/// open write to the same file.
+pub fn register(key: &str, config: Option<&AccessLogConfig>) {
+    let mut logs = LOGS.lock().unwrap();
+    match config {
+        Some(config) => logs.insert(key.to_string(), Arc::new(AccessLog::new(config))),
+        None => logs.remove(key),
+    };
+}
+
+pub fn get(key: &str) -> Option<Arc<AccessLog>> {
+    LOGS.lock().unwrap().get(key).cloned()
+}
+
+pub fn reopen_all() {
+    for log in LOGS.lock().unwrap().values() {
+        log.reopen();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn size_rotation() {
+        let dir = std::env::temp_dir().join(format!("proxy-access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = AccessLog::new(&AccessLogConfig {
+            path: dir.join("access.log"),
+            format: None,
+            max_size: Some(10),
+            rotate: None,
+            keep: Some(2),
+        });
+        for line in ["first", "second", "third", "fourth"] {
+            log.write(line);
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("access.log"), "fourth\n");
+        assert_eq!(read("access.log.1"), "third\n");
+        assert_eq!(read("access.log.2"), "second\n");
+        assert!(!dir.join("access.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
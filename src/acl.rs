@@ -0,0 +1,427 @@
+//! Ordered allow/deny rules on IP address ranges and ports
+//!
+//! Rules are written as `"allow 10.0.0.0/8"`, `"deny ::1"`,
+//! `"deny internal"` or `"allow all port 80,443"`. The first rule
+//! matching an address decides; if none matches, the opposite of the
+//! last rule applies, so a list of `allow` rules denies everything else
+//! and a list of `deny` rules allows it.
+
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+
+/// Address range, IPv4-mapped IPv6 ranges are stored as IPv4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    const fn v4(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Cidr {
+        Cidr {
+            addr: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+            prefix,
+        }
+    }
+
+    const fn v6(first: u16, last: u16, prefix: u8) -> Cidr {
+        Cidr {
+            addr: IpAddr::V6(Ipv6Addr::new(first, 0, 0, 0, 0, 0, 0, last)),
+            prefix,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address \"{}\"", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|p| *p <= max),
+            None => Some(max),
+        }
+        .ok_or_else(|| format!("invalid prefix length in \"{}\"", s))?;
+        Ok(match addr {
+            IpAddr::V6(v6) if prefix >= 96 && v6.to_ipv4_mapped().is_some() => Cidr {
+                addr: IpAddr::V4(v6.to_ipv4_mapped().unwrap()),
+                prefix: prefix - 96,
+            },
+            _ => Cidr { addr, prefix },
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Named set of ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    Loopback,
+    Private,
+    LinkLocal,
+    /// all of the above and unspecified addresses: everything that is not
+    /// a public host
+    Internal,
+}
+
+const LOOPBACK: &[Cidr] = &[Cidr::v4(127, 0, 0, 0, 8), Cidr::v6(0, 1, 128)];
+const PRIVATE: &[Cidr] = &[
+    Cidr::v4(10, 0, 0, 0, 8),
+    Cidr::v4(172, 16, 0, 0, 12),
+    Cidr::v4(192, 168, 0, 0, 16),
+    Cidr::v4(100, 64, 0, 0, 10),
+    Cidr::v6(0xfc00, 0, 7),
+];
+const LINK_LOCAL: &[Cidr] = &[Cidr::v4(169, 254, 0, 0, 16), Cidr::v6(0xfe80, 0, 10)];
+const UNSPECIFIED: &[Cidr] = &[Cidr::v4(0, 0, 0, 0, 8), Cidr::v6(0, 0, 128)];
+
+impl Preset {
+    const ALL: [Preset; 4] = [
+        Preset::Loopback,
+        Preset::Private,
+        Preset::LinkLocal,
+        Preset::Internal,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Preset::Loopback => "loopback",
+            Preset::Private => "private",
+            Preset::LinkLocal => "link_local",
+            Preset::Internal => "internal",
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let sets: &[&[Cidr]] = match self {
+            Preset::Loopback => &[LOOPBACK],
+            Preset::Private => &[PRIVATE],
+            Preset::LinkLocal => &[LINK_LOCAL],
+            Preset::Internal => &[LOOPBACK, PRIVATE, LINK_LOCAL, UNSPECIFIED],
+        };
+        sets.iter()
+            .flat_map(|set| set.iter())
+            .any(|net| net.contains(ip))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Range {
+    All,
+    Net(Cidr),
+    Preset(Preset),
+}
+
+impl Range {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match self {
+            Range::All => true,
+            Range::Net(net) => net.contains(ip),
+            Range::Preset(preset) => preset.contains(ip),
+        }
+    }
+}
+
+impl FromStr for Range {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Range::All);
+        }
+        match Preset::ALL.iter().find(|p| p.name() == s) {
+            Some(preset) => Ok(Range::Preset(*preset)),
+            None => Ok(Range::Net(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Range::All => f.write_str("all"),
+            Range::Net(net) => write!(f, "{}", net),
+            Range::Preset(preset) => f.write_str(preset.name()),
+        }
+    }
+}
+
+/// Port list like `80,443,8000-8999`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Ports(Vec<(u16, u16)>);
+
+impl Ports {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&port))
+    }
+}
+
+impl FromStr for Ports {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port list \"{}\"", s);
+        s.split(',')
+            .map(|item| {
+                let item = item.trim();
+                let (first, last) = item.split_once('-').unwrap_or((item, item));
+                match (first.parse::<u16>(), last.parse::<u16>()) {
+                    (Ok(first), Ok(last)) if first <= last => Ok((first, last)),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Ports)
+    }
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (first, last)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match first == last {
+                true => write!(f, "{}", first)?,
+                false => write!(f, "{}-{}", first, last)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Ports> for String {
+    fn from(ports: Ports) -> String {
+        ports.to_string()
+    }
+}
+
+impl TryFrom<String> for Ports {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+    pub action: Action,
+    pub range: Range,
+    /// `None` matches every port
+    pub ports: Option<Ports>,
+}
+
+impl Rule {
+    fn matches(&self, ip: IpAddr, port: Option<u16>) -> bool {
+        self.range.contains(ip)
+            && self
+                .ports
+                .as_ref()
+                .is_none_or(|ports| port.is_some_and(|p| ports.contains(p)))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (action, range, ports) = match words[..] {
+            [action, range] => (action, range, None),
+            [action, range, "port", ports] => (action, range, Some(ports.parse()?)),
+            _ => {
+                return Err(format!(
+                    "rule \"{}\" is not `allow|deny <range> [port <ports>]`",
+                    s
+                ))
+            }
+        };
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(format!("unknown action \"{}\" in rule \"{}\"", action, s)),
+        };
+        Ok(Rule {
+            action,
+            range: range.parse()?,
+            ports,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.action {
+            Action::Allow => "allow ",
+            Action::Deny => "deny ",
+        })?;
+        write!(f, "{}", self.range)?;
+        match &self.ports {
+            Some(ports) => write!(f, " port {}", ports),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Rule> for String {
+    fn from(rule: Rule) -> String {
+        rule.to_string()
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn decide(rules: &[Rule], ip: IpAddr, port: Option<u16>) -> bool {
+    match rules.iter().find(|r| r.matches(ip, port)) {
+        Some(rule) => rule.action == Action::Allow,
+        None => rules.last().is_none_or(|r| r.action == Action::Deny),
+    }
+}
+
+/// Whether `rules` let client `ip` through, empty rules allow everything.
+/// Rules with ports never match clients.
+pub fn allows(rules: &[Rule], ip: IpAddr) -> bool {
+    decide(rules, ip, None)
+}
+
+/// Whether `rules` let connections to `target` through
+pub fn allows_target(rules: &[Rule], target: SocketAddr) -> bool {
+    decide(rules, target.ip(), Some(target.port()))
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    /// every address of the target is denied
+    Denied,
+    Unreachable,
+}
+
+/// Resolve `target` and connect to the first of its addresses `rules`
+/// allow. Denied addresses are skipped, so a name that resolves to
+/// internal addresses can not be used to reach them.
+pub async fn connect<A>(rules: &[Rule], target: A) -> Result<TcpStream, ConnectError>
+where
+    A: ToSocketAddrs,
+{
+    let addrs: Vec<SocketAddr> = lookup_host(target)
+        .await
+        .or(Err(ConnectError::Unreachable))?
+        .collect();
+    let allowed: Vec<SocketAddr> = addrs
+        .iter()
+        .copied()
+        .filter(|addr| allows_target(rules, *addr))
+        .collect();
+    if allowed.is_empty() && !addrs.is_empty() {
+        return Err(ConnectError::Denied);
+    }
+    TcpStream::connect(&allowed[..])
+        .await
+        .or(Err(ConnectError::Unreachable))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn rules(list: &[&str]) -> Vec<Rule> {
+        list.iter().map(|r| r.parse().unwrap()).collect()
+    }
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    #[test]
+    fn cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        let mapped: Cidr = "::ffff:192.168.0.0/112".parse().unwrap();
+        assert_eq!(mapped, "192.168.0.0/16".parse().unwrap());
+        assert!("fe80::/10".parse::<Cidr>().unwrap().contains(ip("fe80::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+    #[test]
+    fn first_match_decides() {
+        let list = rules(&["deny 10.0.0.1", "allow 10.0.0.0/8", "allow ::1"]);
+        assert!(!allows(&list, ip("10.0.0.1")));
+        assert!(allows(&list, ip("10.0.0.2")));
+        assert!(allows(&list, ip("::1")));
+        assert!(!allows(&list, ip("192.168.0.1")));
+        assert!(allows(&rules(&["deny 10.0.0.0/8"]), ip("192.168.0.1")));
+        assert!(allows(&[], ip("192.168.0.1")));
+        assert_eq!(list[1].to_string(), "allow 10.0.0.0/8");
+        assert!("permit all".parse::<Rule>().is_err());
+    }
+    #[test]
+    fn presets_and_ports() {
+        let list = rules(&[
+            "deny internal",
+            "allow all port 80,443,8000-8099",
+            "deny all",
+        ]);
+        let target = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(allows_target(&list, target("93.184.216.34:443")));
+        assert!(allows_target(&list, target("[2001:db8::1]:8080")));
+        assert!(!allows_target(&list, target("93.184.216.34:22")));
+        for internal in [
+            "127.0.0.1:80",
+            "[::1]:80",
+            "169.254.169.254:80",
+            "10.1.2.3:443",
+        ] {
+            assert!(!allows_target(&list, target(internal)), "{}", internal);
+        }
+        assert!(!allows_target(&list, target("[::ffff:192.168.1.1]:80")));
+        assert!(!allows_target(&list, target("[fd00::1]:80")));
+        assert!(!allows_target(&list, target("0.0.0.0:80")));
+        assert!(allows_target(
+            &rules(&["deny loopback"]),
+            target("10.0.0.1:80")
+        ));
+        assert_eq!(list[1].to_string(), "allow all port 80,443,8000-8099");
+        assert!("allow all port 443-80".parse::<Rule>().is_err());
+        assert!("allow all ports 443".parse::<Rule>().is_err());
+    }
+}
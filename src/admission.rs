@@ -0,0 +1,169 @@
+//! Admission of accepted clients: client address rules and limits of
+//! concurrent sessions of an engine, overall and per client address
+
+use crate::acl::{self, Rule};
+use crate::config_loader::CommonConfig;
+use crate::listener::{PeerAddr, Stream};
+use crate::logger;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Reason a session is refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    /// client address is denied by `client_acl`
+    ClientDenied,
+    MaxConnections,
+    MaxConnectionsPerIp,
+}
+
+impl Refusal {
+    /// Kind of a refused session
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Refusal::ClientDenied => "client_denied",
+            Refusal::MaxConnections => "max_connections",
+            Refusal::MaxConnectionsPerIp => "max_connections_per_ip",
+        }
+    }
+}
+
+/// Decides which accepted clients an engine serves
+pub struct Admission {
+    clients: Vec<Rule>,
+    limits: Arc<ConnectionLimits>,
+}
+
+impl Admission {
+    pub fn new(config: &CommonConfig) -> Admission {
+        Admission {
+            clients: config.client_acl.clone().unwrap_or_default(),
+            limits: ConnectionLimits::new(config.max_connections, config.max_connections_per_ip),
+        }
+    }
+
+    /// Check client address and count its session. Clients of unix
+    /// socket endpoints have no address and are only counted.
+    pub fn admit(&self, client: Option<IpAddr>) -> Result<Permit, Refusal> {
+        if client.is_some_and(|ip| !acl::allows(&self.clients, ip)) {
+            return Err(Refusal::ClientDenied);
+        }
+        self.limits.acquire(client)
+    }
+}
+
+/// Refuse client right after accept, without reading from it: `reply` is
+/// sent if the socket takes it at once, then the connection is closed
+pub fn refuse(
+    engine: (&'static str, &str),
+    sock: Stream,
+    client: &PeerAddr,
+    refusal: Refusal,
+    reply: &[u8],
+) {
+    if !reply.is_empty() {
+        sock.send_now(reply).ok();
+    }
+    log_refused(engine, client, refusal);
+}
+
+/// Log refusal of `client`, for engines that answer it themselves
+pub fn log_refused(engine: (&'static str, &str), client: &PeerAddr, refusal: Refusal) {
+    logger::warn("session refused")
+        .engine(engine.0, engine.1)
+        .client(client)
+        .error(refusal.kind())
+        .log();
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+struct ConnectionLimits {
+    max: Option<usize>,
+    per_ip: Option<usize>,
+    counts: Mutex<Counts>,
+}
+
+impl ConnectionLimits {
+    fn new(max: Option<usize>, per_ip: Option<usize>) -> Arc<ConnectionLimits> {
+        Arc::new(ConnectionLimits {
+            max,
+            per_ip,
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+
+    /// Count a new session from `client`, unless it is over a limit.
+    /// Session is counted until the permit is dropped.
+    fn acquire(self: &Arc<Self>, client: Option<IpAddr>) -> Result<Permit, Refusal> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.max.is_some_and(|max| counts.total >= max) {
+            return Err(Refusal::MaxConnections);
+        }
+        let client = client.filter(|_| self.per_ip.is_some());
+        if let (Some(per_ip), Some(ip)) = (self.per_ip, client) {
+            let count = counts.by_ip.entry(ip).or_default();
+            if *count >= per_ip {
+                return Err(Refusal::MaxConnectionsPerIp);
+            }
+            *count += 1;
+        }
+        counts.total += 1;
+        Ok(Permit {
+            limits: self.clone(),
+            client,
+        })
+    }
+
+    fn release(&self, client: Option<IpAddr>) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = client {
+            if let Some(count) = counts.by_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Session counted by `ConnectionLimits`
+pub struct Permit {
+    limits: Arc<ConnectionLimits>,
+    client: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limits.release(self.client);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn limits() {
+        let limits = ConnectionLimits::new(Some(3), Some(2));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = limits.acquire(Some(a)).unwrap();
+        let _second = limits.acquire(Some(a)).unwrap();
+        assert_eq!(
+            limits.acquire(Some(a)).err(),
+            Some(Refusal::MaxConnectionsPerIp)
+        );
+        let _third = limits.acquire(Some(b)).unwrap();
+        assert_eq!(limits.acquire(None).err(), Some(Refusal::MaxConnections));
+        drop(first);
+        assert!(limits.acquire(Some(a)).is_ok());
+        assert_eq!(limits.counts.lock().unwrap().total, 2);
+    }
+}
@@ -0,0 +1,171 @@
+//! Relay buffers shared by all sessions
+//!
+//! Sessions take a buffer only when data is ready to be copied and give
+//! it back when they wait again, so idle sessions hold no buffer memory.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+pub const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+pub const MIN_BUFFER_SIZE: usize = 512;
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Free buffers kept for reuse, in bytes over all sizes
+const MAX_IDLE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// buffers taken by sessions now
+    pub in_use: usize,
+    pub in_use_bytes: usize,
+    /// free buffers kept for reuse
+    pub idle: usize,
+    pub idle_bytes: usize,
+    /// buffers allocated since start
+    pub allocated: u64,
+    /// requests served by a free buffer since start
+    pub reused: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "in_use={} in_use_bytes={} idle={} idle_bytes={} allocated={} reused={}",
+            self.in_use, self.in_use_bytes, self.idle, self.idle_bytes, self.allocated, self.reused
+        )
+    }
+}
+
+/// Free buffers by size
+type FreeLists = BTreeMap<usize, Vec<Box<[u8]>>>;
+
+pub struct Pool {
+    free: Mutex<(FreeLists, Stats)>,
+}
+
+impl Pool {
+    pub const fn new() -> Pool {
+        Pool {
+            free: Mutex::new((
+                BTreeMap::new(),
+                Stats {
+                    in_use: 0,
+                    in_use_bytes: 0,
+                    idle: 0,
+                    idle_bytes: 0,
+                    allocated: 0,
+                    reused: 0,
+                },
+            )),
+        }
+    }
+
+    pub fn get(&self, size: usize) -> Buffer<'_> {
+        let mut guard = self.free.lock().unwrap();
+        let (free, stats) = &mut *guard;
+        let data = match free.get_mut(&size).and_then(|v| v.pop()) {
+            Some(data) => {
+                stats.idle -= 1;
+                stats.idle_bytes -= size;
+                stats.reused += 1;
+                data
+            }
+            None => {
+                stats.allocated += 1;
+                vec![0u8; size].into_boxed_slice()
+            }
+        };
+        stats.in_use += 1;
+        stats.in_use_bytes += size;
+        Buffer {
+            data: Some(data),
+            pool: self,
+        }
+    }
+
+    fn put(&self, data: Box<[u8]>) {
+        let mut guard = self.free.lock().unwrap();
+        let (free, stats) = &mut *guard;
+        let size = data.len();
+        stats.in_use -= 1;
+        stats.in_use_bytes -= size;
+        if stats.idle_bytes + size <= MAX_IDLE_BYTES {
+            stats.idle += 1;
+            stats.idle_bytes += size;
+            free.entry(size).or_default().push(data);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.free.lock().unwrap().1
+    }
+}
+
+/// Buffer taken from pool, returned when dropped
+pub struct Buffer<'p> {
+    data: Option<Box<[u8]>>,
+    pool: &'p Pool,
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            self.pool.put(data);
+        }
+    }
+}
+
+static POOL: Pool = Pool::new();
+
+/// Take buffer of `size` bytes from the shared pool
+pub fn get(size: usize) -> Buffer<'static> {
+    POOL.get(size)
+}
+
+pub fn stats() -> Stats {
+    POOL.stats()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn reuse() {
+        let pool = Pool::new();
+        let a = pool.get(1024);
+        let b = pool.get(2048);
+        assert_eq!(b.len(), 2048);
+        drop(a);
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.idle, stats.idle_bytes), (1, 1, 1024));
+        let _c = pool.get(1024);
+        drop(b);
+        assert_eq!(
+            pool.stats(),
+            Stats {
+                in_use: 1,
+                in_use_bytes: 1024,
+                idle: 1,
+                idle_bytes: 2048,
+                allocated: 2,
+                reused: 1,
+            }
+        );
+    }
+}
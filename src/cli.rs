@@ -0,0 +1,199 @@
+//! Command line parsing
+
+use crate::config_loader::{self, Config, ConfigError};
+use crate::config_validator;
+
+pub const USAGE: &str = "\
+usage: proxy [command] [config_file | engine flags]
+
+commands:
+  run [config_file]                 start proxy (default command)
+  check [config_file]               validate configuration and exit
+  print-default-config              print built-in default configuration
+  print-effective-config [config]   print configuration as it will be used
+
+engine flags, used instead of config_file:
+  --http <listen>                   http proxy
+  --socks4 <listen>                 socks4 proxy
+  --socks5 <listen>                 socks5 proxy
+  --tcppm <listen>=<host:port>      tcp port mapper
+  where <listen> is a port or an endpoint like 127.0.0.1:3128
+
+options:
+  --check <config_file>             same as `check`
+  -V, --version                     print version
+  -h, --help                        print this help";
+
+/// Where configuration comes from
+#[derive(Debug, PartialEq)]
+pub enum ConfigSource {
+    Default,
+    File(String),
+    /// engines given by command line flags
+    Flags(toml::Table),
+}
+
+impl ConfigSource {
+    pub fn path(&self) -> Option<String> {
+        match self {
+            ConfigSource::File(path) => Some(path.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        match self {
+            ConfigSource::Default => Ok(config_loader::load_config_default()),
+            ConfigSource::File(path) => config_loader::load_config(path),
+            ConfigSource::Flags(table) => {
+                let config: Config = table
+                    .clone()
+                    .try_into()
+                    .map_err(|e: toml::de::Error| ConfigError::new(e.message().trim()))?;
+                config_validator::validate(&config)?;
+                Ok(config)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(ConfigSource),
+    Check(ConfigSource),
+    PrintDefaultConfig,
+    PrintEffectiveConfig(ConfigSource),
+    Version,
+    Help,
+}
+
+/// Add engine given by `--<engine_type> <value>` flag to `table`
+fn add_engine(table: &mut toml::Table, engine_type: &str, value: &str) -> Result<(), String> {
+    let mut engine = toml::Table::new();
+    let listen = if engine_type == "tcppm" {
+        let (listen, target) = value
+            .split_once('=')
+            .ok_or_else(|| format!("--tcppm expects <listen>=<host:port>, got \"{}\"", value))?;
+        engine.insert("target".to_string(), target.into());
+        listen
+    } else {
+        value
+    };
+    engine.insert("listen".to_string(), listen.into());
+    let engines = table
+        .entry(engine_type)
+        .or_insert_with(|| toml::Table::new().into())
+        .as_table_mut()
+        .unwrap();
+    engines.insert((engines.len() + 1).to_string(), engine.into());
+    Ok(())
+}
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut command = None;
+    let mut path = None;
+    let mut flags = toml::Table::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-V" | "--version" => return Ok(Command::Version),
+            "-h" | "--help" => return Ok(Command::Help),
+            "--http" | "--socks4" | "--socks5" | "--tcppm" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                add_engine(&mut flags, &arg[2..], &value)?;
+            }
+            "--check" => {
+                command.get_or_insert_with(|| "check".to_string());
+                path = Some(
+                    args.next()
+                        .ok_or_else(|| "--check requires a config file".to_string())?,
+                );
+            }
+            "run" | "check" | "print-default-config" | "print-effective-config"
+                if command.is_none() && path.is_none() =>
+            {
+                command = Some(arg)
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    let source = match (path, flags.is_empty()) {
+        (Some(_), false) => {
+            return Err("config file and engine flags can not be used together".to_string())
+        }
+        (Some(path), true) => ConfigSource::File(path),
+        (None, false) => ConfigSource::Flags(flags),
+        (None, true) => ConfigSource::Default,
+    };
+    match command.as_deref().unwrap_or("run") {
+        "run" => Ok(Command::Run(source)),
+        "check" => Ok(Command::Check(source)),
+        "print-effective-config" => Ok(Command::PrintEffectiveConfig(source)),
+        _ if source != ConfigSource::Default => {
+            Err("print-default-config takes no arguments".to_string())
+        }
+        _ => Ok(Command::PrintDefaultConfig),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn p(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|s| s.to_string()))
+    }
+    #[test]
+    fn commands() {
+        assert_eq!(p(&[]), Ok(Command::Run(ConfigSource::Default)));
+        assert_eq!(
+            p(&["a.toml"]),
+            Ok(Command::Run(ConfigSource::File("a.toml".to_string())))
+        );
+        assert_eq!(
+            p(&["run", "a.toml"]),
+            Ok(Command::Run(ConfigSource::File("a.toml".to_string())))
+        );
+        assert_eq!(
+            p(&["--check", "a.toml"]),
+            Ok(Command::Check(ConfigSource::File("a.toml".to_string())))
+        );
+        assert_eq!(p(&["check"]), Ok(Command::Check(ConfigSource::Default)));
+        assert_eq!(
+            p(&["print-default-config"]),
+            Ok(Command::PrintDefaultConfig)
+        );
+        assert_eq!(p(&["run", "--version"]), Ok(Command::Version));
+        assert!(p(&["print-default-config", "a.toml"]).is_err());
+        assert!(p(&["a.toml", "b.toml"]).is_err());
+        assert!(p(&["--bogus"]).is_err());
+        assert!(p(&["a.toml", "--http", "3128"]).is_err());
+    }
+    #[test]
+    fn engine_flags() {
+        let Ok(Command::Run(source)) = p(&[
+            "--http",
+            "3128",
+            "--socks5",
+            "127.0.0.1:1080",
+            "--tcppm",
+            "9000=host:80",
+            "--http",
+            "8080",
+        ]) else {
+            panic!()
+        };
+        let config = source.load().unwrap();
+        assert_eq!(config.http.len(), 2);
+        assert_eq!(config.http["2"].common.endpoints()[0].to_string(), "*:8080");
+        assert_eq!(
+            config.socks5["1"].common.endpoints()[0].to_string(),
+            "127.0.0.1:1080"
+        );
+        assert_eq!(config.tcppm["1"].target, "host:80");
+        assert!(p(&["--tcppm", "9000"]).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! `[defaults]` section inherited by engines
+//!
+//! Values from `[defaults]` apply to every engine, values from
+//! `[defaults.<type>]` to engines of that type. Engine's own values
+//! override inherited ones.
+
+use std::collections::BTreeMap;
+
+pub struct Defaults {
+    common: toml::Table,
+    types: BTreeMap<String, toml::Table>,
+}
+
+/// Merge `over` into `base`. Tables are merged recursively, other values
+/// from `over` replace values in `base`.
+pub fn merge(base: &mut toml::Table, over: &toml::Table) {
+    for (k, v) in over {
+        match (base.get_mut(k), v) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            _ => {
+                base.insert(k.clone(), v.clone());
+            }
+        }
+    }
+}
+
+impl Defaults {
+    /// Remove `defaults` section from configuration `table`
+    pub fn take(table: &mut toml::Table, engine_types: &[&str]) -> Result<Defaults, String> {
+        let mut common = match table.remove("defaults") {
+            None => toml::Table::new(),
+            Some(toml::Value::Table(t)) => t,
+            Some(_) => return Err("`defaults` must be a table".to_string()),
+        };
+        let mut types = BTreeMap::new();
+        for engine_type in engine_types {
+            match common.remove(*engine_type) {
+                None => (),
+                Some(toml::Value::Table(t)) => {
+                    types.insert(engine_type.to_string(), t);
+                }
+                Some(_) => return Err(format!("`defaults.{}` must be a table", engine_type)),
+            }
+        }
+        Ok(Defaults { common, types })
+    }
+
+    /// Values inherited by engines of `engine_type`
+    pub fn for_type(&self, engine_type: &str) -> toml::Table {
+        let mut result = self.common.clone();
+        if let Some(t) = self.types.get(engine_type) {
+            merge(&mut result, t);
+        }
+        result
+    }
+
+    /// Full path of inherited key, `defaults.<type>.key` or `defaults.key`
+    pub fn key_path(&self, engine_type: &str, path: &[String]) -> Vec<String> {
+        let mut result = vec!["defaults".to_string()];
+        let in_type = self
+            .types
+            .get(engine_type)
+            .is_some_and(|t| path.first().is_some_and(|k| t.contains_key(k)));
+        if in_type {
+            result.push(engine_type.to_string());
+        }
+        result.extend_from_slice(path);
+        result
+    }
+
+    /// Apply defaults to `engine` configuration of `engine_type`
+    pub fn apply(&self, engine_type: &str, engine: &mut toml::Table) {
+        let mut result = self.for_type(engine_type);
+        merge(&mut result, engine);
+        *engine = result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn inheritance() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            [defaults]
+            unix_mode = 0o600
+            unix_owner = "proxy"
+            [defaults.http]
+            unix_mode = 0o660
+            [http.a]
+            unix_owner = "www"
+            "#,
+        )
+        .unwrap();
+        let defaults = Defaults::take(&mut table, &["http", "socks5"]).unwrap();
+        assert!(!table.contains_key("defaults"));
+        let mut http: toml::Table = table["http"]["a"].as_table().unwrap().clone();
+        defaults.apply("http", &mut http);
+        assert_eq!(http["unix_mode"].as_integer(), Some(0o660));
+        assert_eq!(http["unix_owner"].as_str(), Some("www"));
+        let socks5 = defaults.for_type("socks5");
+        assert_eq!(socks5["unix_mode"].as_integer(), Some(0o600));
+        assert_eq!(socks5["unix_owner"].as_str(), Some("proxy"));
+    }
+}
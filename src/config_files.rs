@@ -0,0 +1,380 @@
+//! Reading configuration files: includes, environment variables and
+//! mapping of configuration keys back to file and line for error messages
+
+use crate::config_loader::{ConfigError, ENGINE_TYPES};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Nesting limit for included files, protects from include loops
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Text of one configuration file
+struct Source {
+    path: String,
+    text: String,
+}
+
+impl Source {
+    fn line(&self, offset: usize) -> usize {
+        self.text[..offset.min(self.text.len())]
+            .matches('\n')
+            .count()
+            + 1
+    }
+
+    fn toml_error(&self, e: &toml::de::Error) -> String {
+        match e.span() {
+            Some(span) => format!(
+                "{}:{}: {}",
+                self.path,
+                self.line(span.start),
+                e.message().trim()
+            ),
+            None => format!("{}: {}", self.path, e.message().trim()),
+        }
+    }
+
+    /// Line where key given by `path` of nested tables is defined
+    fn key_line(&self, path: &[String]) -> Option<usize> {
+        let root = toml::de::DeTable::parse(&self.text).ok()?;
+        let mut table = root.get_ref();
+        let (last, parents) = path.split_last()?;
+        for key in parents {
+            table = table.get(key.as_str())?.get_ref().as_table()?;
+        }
+        let (key, _) = table.get_key_value(last.as_str())?;
+        Some(self.line(key.span().start))
+    }
+
+    /// `file:line` of the key or of the closest parent table found
+    fn location(&self, path: &[String]) -> String {
+        (1..=path.len())
+            .rev()
+            .find_map(|len| self.key_line(&path[..len]))
+            .map_or_else(
+                || self.path.clone(),
+                |line| format!("{}:{}", self.path, line),
+            )
+    }
+}
+
+/// Main configuration file and all files included by it
+pub struct Files {
+    sources: Vec<Source>,
+    /// index of source defining each `type.name` engine
+    origins: BTreeMap<String, usize>,
+}
+
+impl Files {
+    /// Read configuration file with its includes, returns combined table
+    pub fn load(path: &str) -> Result<(Files, toml::Table), ConfigError> {
+        let text =
+            fs::read_to_string(path).map_err(|e| ConfigError::new(format!("{}: {}", path, e)))?;
+        Files::from_text(path, text)
+    }
+
+    pub fn from_text<S: Into<String>>(
+        path: &str,
+        text: S,
+    ) -> Result<(Files, toml::Table), ConfigError> {
+        let mut files = Files {
+            sources: Vec::new(),
+            origins: BTreeMap::new(),
+        };
+        let table = files.add(path.to_string(), text.into(), 0)?;
+        Ok((files, table))
+    }
+
+    pub fn main_path(&self) -> &str {
+        &self.sources[0].path
+    }
+
+    /// `file:line` of configuration key given by `path`
+    pub fn location(&self, path: &[String]) -> String {
+        let origin = match path {
+            [engine_type, name, ..] if ENGINE_TYPES.contains(&engine_type.as_str()) => self
+                .origins
+                .get(&format!("{}.{}", engine_type, name))
+                .copied()
+                .unwrap_or(0),
+            _ => 0,
+        };
+        self.sources[origin].location(path)
+    }
+
+    /// Parse file, substitute environment variables and process includes
+    fn add(
+        &mut self,
+        path: String,
+        text: String,
+        depth: usize,
+    ) -> Result<toml::Table, ConfigError> {
+        let index = self.sources.len();
+        self.sources.push(Source { path, text });
+        let source = &self.sources[index];
+        let mut table: toml::Table =
+            toml::from_str(&source.text).map_err(|e| ConfigError::new(source.toml_error(&e)))?;
+        let mut errors = Vec::new();
+        interpolate_table(&mut table, &mut Vec::new(), &mut |path, e| {
+            errors.push(format!("{}: {}", source.location(path), e))
+        });
+        for engine_type in ENGINE_TYPES {
+            if let Some(toml::Value::Table(engines)) = table.get(engine_type) {
+                for name in engines.keys() {
+                    let key = format!("{}.{}", engine_type, name);
+                    if let Some(other) = self.origins.insert(key.clone(), index) {
+                        errors.push(format!(
+                            "{}: [{}] is already defined in {}",
+                            source.location(&[engine_type.to_string(), name.clone()]),
+                            key,
+                            self.sources[other].path
+                        ));
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ConfigError(errors));
+        }
+        let includes = match table.remove("include") {
+            None => Vec::new(),
+            Some(toml::Value::String(s)) => vec![s],
+            Some(toml::Value::Array(a)) => a
+                .into_iter()
+                .map(|v| v.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    ConfigError::new(format!(
+                        "{}: `include` must be a list of strings",
+                        source.location(&["include".to_string()])
+                    ))
+                })?,
+            Some(_) => {
+                return Err(ConfigError::new(format!(
+                    "{}: `include` must be a list of strings",
+                    source.location(&["include".to_string()])
+                )))
+            }
+        };
+        if !includes.is_empty() && depth >= MAX_INCLUDE_DEPTH {
+            return Err(ConfigError::new(format!(
+                "{}: includes are nested too deep",
+                source.location(&["include".to_string()])
+            )));
+        }
+        let dir = Path::new(&source.path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let location = source.location(&["include".to_string()]);
+        for pattern in includes {
+            let paths = expand(&dir.join(&pattern))
+                .map_err(|e| ConfigError::new(format!("{}: {}: {}", location, pattern, e)))?;
+            for path in paths {
+                let path = path.to_string_lossy().into_owned();
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| ConfigError::new(format!("{}: {}: {}", location, path, e)))?;
+                let included_index = self.sources.len();
+                let included = self.add(path, text, depth + 1)?;
+                merge_engines(&mut table, included, &self.sources[included_index])?;
+            }
+        }
+        Ok(table)
+    }
+}
+
+/// Move engines from `included` file to `table`
+fn merge_engines(
+    table: &mut toml::Table,
+    included: toml::Table,
+    source: &Source,
+) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+    for (k, v) in included {
+        match v {
+            toml::Value::Table(engines) if ENGINE_TYPES.contains(&k.as_str()) => {
+                let target = table.entry(k).or_insert_with(|| toml::Table::new().into());
+                if let toml::Value::Table(target) = target {
+                    target.extend(engines);
+                }
+            }
+            _ => errors.push(format!(
+                "{}: only engine sections are allowed in included files, found `{}`",
+                source.location(std::slice::from_ref(&k)),
+                k
+            )),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+/// Whether file `name` matches `pattern` with `*` and `?` wildcards
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Files matching `pattern`, wildcards are allowed in file name only.
+/// Pattern without wildcards is returned as is.
+fn expand(pattern: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let Some(name) = pattern.file_name().map(|n| n.to_string_lossy()) else {
+        return Ok(vec![pattern.to_path_buf()]);
+    };
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut result = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        // hidden files are matched only by explicit patterns
+        if file_name.starts_with('.') && !name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_file() && wildcard_match(name.as_bytes(), file_name.as_bytes()) {
+            result.push(entry.path());
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Substitute `${VAR}` and `${VAR:-default}` with environment variables.
+/// `$${` is an escaped `${`.
+fn interpolate(s: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated variable in \"{}\"", s))?;
+        let expr = &rest[start + 2..start + end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(value), _) => result.push_str(&value),
+            (Err(_), Some(default)) => result.push_str(default),
+            (Err(_), None) => return Err(format!("environment variable {} is not set", name)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Interpolate all strings in `table`, calling `error` with path of values
+/// that can not be interpolated
+fn interpolate_table<E>(table: &mut toml::Table, path: &mut Vec<String>, error: &mut E)
+where
+    E: FnMut(&[String], String),
+{
+    fn value<E>(v: &mut toml::Value, path: &mut Vec<String>, error: &mut E)
+    where
+        E: FnMut(&[String], String),
+    {
+        match v {
+            toml::Value::String(s) => match interpolate(s) {
+                Ok(new) => *s = new,
+                Err(e) => error(path, e),
+            },
+            toml::Value::Array(a) => a.iter_mut().for_each(|v| value(v, path, error)),
+            toml::Value::Table(t) => interpolate_table(t, path, error),
+            _ => (),
+        }
+    }
+    for (k, v) in table.iter_mut() {
+        path.push(k.clone());
+        value(v, path, error);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match(b"*.toml", b"a.toml"));
+        assert!(wildcard_match(b"*.toml", b".toml"));
+        assert!(wildcard_match(b"a?c*", b"abcdef"));
+        assert!(!wildcard_match(b"*.toml", b"a.toml.bak"));
+        assert!(!wildcard_match(b"a?c", b"ac"));
+    }
+    #[test]
+    fn env_interpolation() {
+        std::env::set_var("PROXY_TEST_TARGET", "10.0.0.1:80");
+        std::env::remove_var("PROXY_TEST_UNSET");
+        assert_eq!(interpolate("${PROXY_TEST_TARGET}").unwrap(), "10.0.0.1:80");
+        assert_eq!(
+            interpolate("x${PROXY_TEST_UNSET:-host:80}y").unwrap(),
+            "xhost:80y"
+        );
+        assert_eq!(
+            interpolate("$${PROXY_TEST_TARGET}").unwrap(),
+            "${PROXY_TEST_TARGET}"
+        );
+        assert_eq!(interpolate("no vars").unwrap(), "no vars");
+        assert!(interpolate("${PROXY_TEST_UNSET}").is_err());
+        assert!(interpolate("${PROXY_TEST_TARGET").is_err());
+    }
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("proxy_include_test_{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(dir.join("conf.d/a.toml"), "[http.a]\nport = 1\n").unwrap();
+        fs::write(
+            dir.join("conf.d/b.toml"),
+            "[socks5.b]\nport = 2\n[http.c]\nport = 3\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/b.toml.bak"), "garbage").unwrap();
+        let main = dir.join("main.toml");
+        let main = main.to_str().unwrap();
+        let (files, table) = Files::from_text(
+            main,
+            "include = [\"conf.d/*.toml\"]\n[tcppm.t]\nport = 4\ntarget = \"x:1\"\n",
+        )
+        .unwrap();
+        assert_eq!(table["http"].as_table().unwrap().len(), 2);
+        assert!(table["socks5"].get("b").is_some());
+        assert!(files
+            .location(&["socks5".to_string(), "b".to_string()])
+            .ends_with("b.toml:1"));
+        let errors = Files::from_text(main, "include = \"conf.d/*.toml\"\n[http.a]\nport = 5\n")
+            .err()
+            .unwrap();
+        assert!(
+            errors.0[0].contains("[http.a] is already defined in"),
+            "{}",
+            errors
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
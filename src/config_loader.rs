@@ -1,51 +1,499 @@
-use serde_derive::Deserialize;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
+use crate::access_log::AccessLogConfig;
+use crate::acl::{Ports, Rule};
+use crate::config_defaults::Defaults;
+use crate::config_files::Files;
+use crate::config_validator;
+use crate::listener::ListenAddr;
+use crate::logger::LogConfig;
+use crate::throttle::ThrottleConfig;
+use crate::util::{RelayMode, RelayOptions};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize as _, Serialize};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Deserialize, Debug, Clone, Default)]
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// Reaction to an engine that fails to bind its endpoints
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BindErrorPolicy {
+    /// log the error and keep other engines running
+    #[default]
+    Continue,
+    /// stop all engines and exit with an error
+    Exit,
+}
+
+/// Scheme of HTTP proxy authentication
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScheme {
+    Basic,
+    Digest,
+}
+
+pub const ENGINE_TYPES: [&str; 4] = ["http", "socks4", "socks5", "tcppm"];
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
 #[serde(default)]
 pub struct Config {
-    pub http: HashMap<String, HttpConfig>,
-    pub socks4: HashMap<String, Socks4Config>,
-    pub socks5: HashMap<String, Socks5Config>,
-    pub tcppm: HashMap<String, TcpPmConfig>,
+    pub http: BTreeMap<String, HttpConfig>,
+    pub socks4: BTreeMap<String, Socks4Config>,
+    pub socks5: BTreeMap<String, Socks5Config>,
+    pub tcppm: BTreeMap<String, TcpPmConfig>,
+    /// seconds to wait for open sessions on shutdown
+    pub shutdown_timeout: Option<u64>,
+    /// what to do when an engine can not bind its endpoints
+    pub on_bind_error: Option<BindErrorPolicy>,
+    pub log: LogConfig,
+}
+
+impl Config {
+    /// Common options of every engine keyed by `type.name`
+    pub fn engine_commons(&self) -> Vec<(String, &CommonConfig)> {
+        let mut result = Vec::new();
+        result.extend(
+            self.http
+                .iter()
+                .map(|(k, v)| (format!("http.{}", k), &v.common)),
+        );
+        result.extend(
+            self.socks4
+                .iter()
+                .map(|(k, v)| (format!("socks4.{}", k), &v.common)),
+        );
+        result.extend(
+            self.socks5
+                .iter()
+                .map(|(k, v)| (format!("socks5.{}", k), &v.common)),
+        );
+        result.extend(
+            self.tcppm
+                .iter()
+                .map(|(k, v)| (format!("tcppm.{}", k), &v.common)),
+        );
+        result
+    }
+}
+
+/// One or more problems found in configuration, one per line
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl ConfigError {
+    pub fn new<S: Into<String>>(message: S) -> ConfigError {
+        ConfigError(vec![message.into()])
+    }
+
+    /// Prefix every message with file name
+    pub fn in_file(self, path: &str) -> ConfigError {
+        ConfigError(
+            self.0
+                .into_iter()
+                .map(|e| format!("{}: {}", path, e))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+/// Keys of `input` that were not consumed by deserialization, i.e. are
+/// missing in `known` produced by serializing deserialized config back.
+fn unknown_keys(
+    input: &toml::Table,
+    known: &toml::Table,
+    path: &mut Vec<String>,
+    result: &mut Vec<Vec<String>>,
+) {
+    for (k, v) in input {
+        path.push(k.clone());
+        match (v, known.get(k)) {
+            (_, None) => result.push(path.clone()),
+            (toml::Value::Table(v), Some(toml::Value::Table(known))) => {
+                unknown_keys(v, known, path, result)
+            }
+            _ => (),
+        }
+        path.pop();
+    }
+}
+
+/// Deserialize configuration of one engine of `engine_type` and return it
+/// serialized back, which contains only keys known for the type
+fn engine_known_keys(
+    engine_type: &str,
+    value: toml::Value,
+) -> Result<toml::Table, toml::de::Error> {
+    fn known<T: DeserializeOwned + Serialize>(
+        value: toml::Value,
+    ) -> Result<toml::Table, toml::de::Error> {
+        let config: T = value.try_into()?;
+        Ok(toml::Table::try_from(&config).expect("config is serializable"))
+    }
+    match engine_type {
+        "http" => known::<HttpConfig>(value),
+        "socks4" => known::<Socks4Config>(value),
+        "socks5" => known::<Socks5Config>(value),
+        "tcppm" => known::<TcpPmConfig>(value),
+        _ => unreachable!("unknown engine type {}", engine_type),
+    }
+}
+
+/// Check keys inherited from `[defaults]` against every engine type
+fn check_defaults(files: &Files, defaults: &Defaults, errors: &mut Vec<String>) {
+    // key path -> engine types it is unknown for
+    let mut unknown: BTreeMap<Vec<String>, Vec<&str>> = BTreeMap::new();
+    for engine_type in ENGINE_TYPES {
+        let inherited = defaults.for_type(engine_type);
+        // required options are not inherited, fill them to check the rest
+        let mut probe = inherited.clone();
+        if engine_type == "tcppm" && !probe.contains_key("target") {
+            probe.insert("target".to_string(), "localhost:1".into());
+        }
+        match engine_known_keys(engine_type, probe.into()) {
+            Ok(known) => {
+                let mut paths = Vec::new();
+                unknown_keys(&inherited, &known, &mut Vec::new(), &mut paths);
+                for path in paths {
+                    let path = defaults.key_path(engine_type, &path);
+                    unknown.entry(path).or_default().push(engine_type);
+                }
+            }
+            Err(e) => errors.push(format!(
+                "{}: [defaults] for {} engines: {}",
+                files.location(&["defaults".to_string()]),
+                engine_type,
+                e.message().trim()
+            )),
+        }
+    }
+    for (path, types) in unknown {
+        let engines = if types.len() == ENGINE_TYPES.len() || path.len() > 2 && path[1] == types[0]
+        {
+            String::new()
+        } else {
+            format!(" for {} engines", types.join(", "))
+        };
+        errors.push(format!(
+            "{}: unknown key `{}`{}",
+            files.location(&path),
+            path.join("."),
+            engines
+        ));
+    }
+}
+
+/// Build configuration from `table` combined from all configuration `files`
+fn parse_config(files: &Files, mut table: toml::Table) -> Result<Config, ConfigError> {
+    let defaults = Defaults::take(&mut table, &ENGINE_TYPES)
+        .map_err(|e| ConfigError::new(format!("{}: {}", files.main_path(), e)))?;
+    let mut errors = Vec::new();
+    check_defaults(files, &defaults, &mut errors);
+    for engine_type in ENGINE_TYPES {
+        let Some(toml::Value::Table(engines)) = table.get_mut(engine_type) else {
+            continue;
+        };
+        for (name, engine) in engines.iter_mut() {
+            let toml::Value::Table(engine) = engine else {
+                continue;
+            };
+            let raw = engine.clone();
+            defaults.apply(engine_type, engine);
+            let mut path = vec![engine_type.to_string(), name.clone()];
+            match engine_known_keys(engine_type, engine.clone().into()) {
+                Ok(known) => {
+                    let mut unknown = Vec::new();
+                    unknown_keys(&raw, &known, &mut path, &mut unknown);
+                    for path in unknown {
+                        errors.push(format!(
+                            "{}: unknown key `{}`",
+                            files.location(&path),
+                            path.join(".")
+                        ));
+                    }
+                }
+                Err(e) => errors.push(format!(
+                    "{}: [{}.{}]: {}",
+                    files.location(&path),
+                    engine_type,
+                    name,
+                    e.message().trim()
+                )),
+            }
+        }
+    }
+    // global options, engines are already checked
+    let mut global = table.clone();
+    global.retain(|k, _| !ENGINE_TYPES.contains(&k));
+    match Config::deserialize(toml::Value::Table(global.clone())) {
+        Ok(config) => {
+            let known = toml::Table::try_from(&config).expect("config is serializable");
+            let mut unknown = Vec::new();
+            unknown_keys(&global, &known, &mut Vec::new(), &mut unknown);
+            for path in unknown {
+                errors.push(format!(
+                    "{}: unknown key `{}`",
+                    files.location(&path),
+                    path.join(".")
+                ));
+            }
+        }
+        Err(e) => errors.push(format!(
+            "{}: {}",
+            files.main_path(),
+            e.to_string().trim().replace('\n', " ")
+        )),
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError(errors));
+    }
+    table.try_into().map_err(|e: toml::de::Error| {
+        ConfigError::new(format!(
+            "{}: {}",
+            files.main_path(),
+            e.to_string().trim().replace('\n', " ")
+        ))
+    })
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Options shared by all engine types
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CommonConfig {
+    /// shorthand for `listen = ["*:<port>"]`
+    pub port: Option<u16>,
+    #[serde(default, deserialize_with = "crate::listener::one_or_many")]
+    pub listen: Vec<ListenAddr>,
+    /// file mode of unix socket endpoints (`0o660`)
+    pub unix_mode: Option<u32>,
+    /// owner (name or uid) of unix socket endpoints
+    pub unix_owner: Option<String>,
+    /// group (name or gid) of unix socket endpoints
+    pub unix_group: Option<String>,
+    /// write access records to a file instead of stdout
+    pub access_log: Option<AccessLogConfig>,
+    /// how sessions relay data, `copy` by default
+    pub relay: Option<RelayMode>,
+    /// size of relay buffers in bytes
+    pub buffer_size: Option<usize>,
+    /// bandwidth limits in bytes per second
+    pub throttle: Option<ThrottleConfig>,
+    /// seconds from accept until the session is established
+    pub handshake_timeout: Option<u64>,
+    /// seconds a session may stay without data
+    pub idle_timeout: Option<u64>,
+    /// seconds a relayed session may last
+    pub max_lifetime: Option<u64>,
+    /// allow/deny rules on client addresses
+    pub client_acl: Option<Vec<Rule>>,
+    /// allow/deny rules on resolved target addresses and ports
+    pub target_acl: Option<Vec<Rule>>,
+    /// concurrent sessions of the engine
+    pub max_connections: Option<usize>,
+    /// concurrent sessions from one client address
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl CommonConfig {
+    /// Rules on targets, shared by sessions of the engine
+    pub fn target_rules(&self) -> Arc<[Rule]> {
+        self.target_acl.clone().unwrap_or_default().into()
+    }
+
+    /// All endpoints the engine should listen on
+    pub fn endpoints(&self) -> Vec<ListenAddr> {
+        let mut result: Vec<ListenAddr> = self.port.map(ListenAddr::Any).into_iter().collect();
+        for addr in &self.listen {
+            if !result.contains(addr) {
+                result.push(addr.clone());
+            }
+        }
+        result
+    }
+
+    pub fn relay_options(&self) -> RelayOptions {
+        let default = RelayOptions::default();
+        RelayOptions {
+            mode: self.relay.unwrap_or(default.mode),
+            buffer_size: self.buffer_size.unwrap_or(default.buffer_size),
+            handshake_timeout: self
+                .handshake_timeout
+                .map_or(default.handshake_timeout, Duration::from_secs),
+            idle_timeout: self
+                .idle_timeout
+                .map_or(default.idle_timeout, Duration::from_secs),
+            max_lifetime: self.max_lifetime.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct HttpConfig {
-    pub port: u16,
+    #[serde(flatten)]
+    pub common: CommonConfig,
+    /// ports CONNECT requests may reach
+    pub connect_ports: Option<Ports>,
+    /// file with host names requests may not reach
+    pub domain_blocklist: Option<PathBuf>,
+    /// file with the only host names requests may reach
+    pub domain_allowlist: Option<PathBuf>,
+    /// users and their passwords or password hashes
+    pub users: Option<BTreeMap<String, String>>,
+    /// htpasswd file with users
+    pub htpasswd: Option<PathBuf>,
+    /// realm of proxy authentication
+    pub auth_realm: Option<String>,
+    /// schemes clients may authenticate with, `basic` if not set
+    pub auth_schemes: Option<Vec<AuthScheme>>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Socks4Config {
-    pub port: u16,
+    #[serde(flatten)]
+    pub common: CommonConfig,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Socks5Config {
-    pub port: u16,
+    #[serde(flatten)]
+    pub common: CommonConfig,
+    /// file with host names requests may not reach
+    pub domain_blocklist: Option<PathBuf>,
+    /// file with the only host names requests may reach
+    pub domain_allowlist: Option<PathBuf>,
+    /// users and their passwords or password hashes
+    pub users: Option<BTreeMap<String, String>>,
+    /// htpasswd file with users
+    pub htpasswd: Option<PathBuf>,
+    /// clients may skip authentication although there are users
+    pub allow_no_auth: Option<bool>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct TcpPmConfig {
-    pub port: u16,
+    #[serde(flatten)]
+    pub common: CommonConfig,
     pub target: String,
 }
 
-pub fn load_config<P: AsRef<str>>(path: P) -> Config {
-    let mut buffer = String::new();
-    File::open(path.as_ref())
-        .unwrap()
-        .read_to_string(&mut buffer)
-        .unwrap();
-    toml::from_str(&buffer).unwrap()
+/// Read, parse and validate configuration file
+pub fn load_config<P: AsRef<str>>(path: P) -> Result<Config, ConfigError> {
+    let path = path.as_ref();
+    let (files, table) = Files::load(path)?;
+    let config = parse_config(&files, table)?;
+    config_validator::validate(&config).map_err(|e| e.in_file(path))?;
+    Ok(config)
 }
 
+/// Configuration used when no file is given
+pub const DEFAULT_CONFIG: &str = "\
+[http.a]
+port = 3128
+";
+
 pub fn load_config_default() -> Config {
-    let t = toml::toml! {
-        [http.a]
-        port = 3128
-    };
-    t.try_into().unwrap()
+    toml::from_str(DEFAULT_CONFIG).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        let (files, table) = Files::from_text("t.toml", text)?;
+        parse_config(&files, table)
+    }
+    #[test]
+    fn listen_endpoints() {
+        let c: Config = toml::from_str(
+            r#"
+            [socks5.local]
+            listen = ["127.0.0.1:1080", "[::1]:1080"]
+            [http.internal]
+            port = 3128
+            listen = ["10.0.0.1:8080", "*:3128"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            c.socks5["local"].common.endpoints(),
+            [
+                ListenAddr::Ip("127.0.0.1:1080".parse().unwrap()),
+                ListenAddr::Ip("[::1]:1080".parse().unwrap())
+            ]
+        );
+        assert_eq!(
+            c.http["internal"].common.endpoints(),
+            [
+                ListenAddr::Any(3128),
+                ListenAddr::Ip("10.0.0.1:8080".parse().unwrap())
+            ]
+        );
+    }
+    #[test]
+    fn listen_single_unix() {
+        let c: Config = toml::from_str(
+            r#"
+            [http.sidecar]
+            listen = "unix:/run/proxy/http.sock"
+            unix_mode = 0o660
+            "#,
+        )
+        .unwrap();
+        let http = &c.http["sidecar"].common;
+        assert_eq!(
+            http.endpoints(),
+            [ListenAddr::Unix("/run/proxy/http.sock".into())]
+        );
+        assert_eq!(http.unix_mode, Some(0o660));
+    }
+    #[test]
+    fn unknown_keys_reported_with_line() {
+        let text = "shutdown_timeout = 5\n[log]\nlevel = \"debug\"\nlevle = 1\n[http.a]\nport = 3128\nprot = 1\n[tcppm.b]\nport = 1\ntarget = \"x:1\"\n[socks6.c]\n";
+        let errors = parse(text).unwrap_err();
+        assert_eq!(
+            errors.0,
+            [
+                "t.toml:7: unknown key `http.a.prot`",
+                "t.toml:4: unknown key `log.levle`",
+                "t.toml:11: unknown key `socks6`"
+            ]
+        );
+    }
+    #[test]
+    fn defaults_inherited() {
+        let text = "[defaults]\nunix_mode = 0o600\n[defaults.tcppm]\ntarget = \"host:80\"\n[http.a]\nport = 3128\n[tcppm.b]\nport = 1\n[tcppm.c]\nport = 2\ntarget = \"other:80\"\n";
+        let c = parse(text).unwrap();
+        assert_eq!(c.http["a"].common.unix_mode, Some(0o600));
+        assert_eq!(c.tcppm["b"].target, "host:80");
+        assert_eq!(c.tcppm["c"].target, "other:80");
+    }
+    #[test]
+    fn unknown_defaults_reported() {
+        let text = "[defaults]\nbogus = 1\ntarget = \"x:1\"\n[defaults.http]\nmore = 2\n";
+        let errors = parse(text).unwrap_err();
+        assert_eq!(
+            errors.0,
+            [
+                "t.toml:2: unknown key `defaults.bogus`",
+                "t.toml:5: unknown key `defaults.http.more`",
+                "t.toml:3: unknown key `defaults.target` for http, socks4, socks5 engines"
+            ]
+        );
+    }
+    #[test]
+    fn type_error_reported_with_line() {
+        let text = "[http.a]\nport = 3128\n[socks4.b]\nport = \"x\"\n";
+        let errors = parse(text).unwrap_err();
+        assert_eq!(errors.0.len(), 1);
+        assert!(errors.0[0].starts_with("t.toml:"), "{}", errors);
+    }
 }
@@ -1,32 +1,226 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use crate::access_log;
+use crate::buffer_pool;
+use crate::config_loader::{
+    self, BindErrorPolicy, CommonConfig, HttpConfig, Socks4Config, Socks5Config, TcpPmConfig,
+};
 use crate::http::Http;
+use crate::listener;
+use crate::logger;
+use crate::session;
+use crate::signals::{Signal, Signals};
 use crate::socks4::Socks4;
 use crate::socks5::Socks5;
+use tokio::task::JoinHandle;
 
 use super::config_loader::Config;
 
-pub async fn spawn(config: Config) {
-    let mut joins = Vec::new();
-    //http
+#[derive(Clone, PartialEq)]
+enum EngineConfig {
+    Http(HttpConfig),
+    Socks4(Socks4Config),
+    Socks5(Socks5Config),
+    TcpPm(TcpPmConfig),
+}
+
+impl EngineConfig {
+    fn common(&self) -> &CommonConfig {
+        match self {
+            EngineConfig::Http(v) => &v.common,
+            EngineConfig::Socks4(v) => &v.common,
+            EngineConfig::Socks5(v) => &v.common,
+            EngineConfig::TcpPm(v) => &v.common,
+        }
+    }
+}
+
+/// Split config into engines keyed by `type.name`
+fn engines(config: Config) -> HashMap<String, EngineConfig> {
+    let mut result = HashMap::new();
     for (k, v) in config.http {
-        let http = Http::new(&k, &v);
-        joins.push(tokio::spawn(async move {http.serve().await}));
+        result.insert(format!("http.{}", k), EngineConfig::Http(v));
     }
-    //socks4
     for (k, v) in config.socks4 {
-        let socks4 = Socks4::new(&k, &v);
-        joins.push(tokio::spawn(async move {socks4.serve().await}));
+        result.insert(format!("socks4.{}", k), EngineConfig::Socks4(v));
     }
-    //socks5
     for (k, v) in config.socks5 {
-        let socks5 = Socks5::new(&k, &v);
-        joins.push(tokio::spawn(async move {socks5.serve().await}));
+        result.insert(format!("socks5.{}", k), EngineConfig::Socks5(v));
     }
-    //tcppm
     for (k, v) in config.tcppm {
-        joins.push(tokio::spawn(async move {
-            super::tcppm::tcppm(k, v.port, v.target).await
-        }));
+        result.insert(format!("tcppm.{}", k), EngineConfig::TcpPm(v));
+    }
+    result
+}
+
+/// Bind endpoints of engine and start serving them
+fn start(key: &str, engine: &EngineConfig) -> io::Result<JoinHandle<()>> {
+    let name = key.split_once('.').map_or(key, |(_type, name)| name);
+    let listeners = listener::bind_all(engine.common())?;
+    access_log::register(key, engine.common().access_log.as_ref());
+    Ok(match engine {
+        EngineConfig::Http(v) => {
+            let http = Http::new(name, v);
+            tokio::spawn(async move { http.serve(listeners).await })
+        }
+        EngineConfig::Socks4(v) => {
+            let socks4 = Socks4::new(name, v);
+            tokio::spawn(async move { socks4.serve(listeners).await })
+        }
+        EngineConfig::Socks5(v) => {
+            let socks5 = Socks5::new(name, v);
+            tokio::spawn(async move { socks5.serve(listeners).await })
+        }
+        EngineConfig::TcpPm(v) => {
+            let (name, v) = (name.to_string(), v.clone());
+            tokio::spawn(async move { super::tcppm::tcppm(name, v, listeners).await })
+        }
+    })
+}
+
+/// Running engines.
+///
+/// Stopping an engine only stops its listeners, sessions that were already
+/// accepted run in their own tasks and are not affected.
+struct Spawner {
+    running: HashMap<String, (EngineConfig, JoinHandle<()>)>,
+    shutdown_timeout: Duration,
+    on_bind_error: BindErrorPolicy,
+}
+
+impl Spawner {
+    fn new() -> Spawner {
+        Spawner {
+            running: HashMap::new(),
+            shutdown_timeout: Duration::from_secs(config_loader::DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            on_bind_error: BindErrorPolicy::default(),
+        }
+    }
+
+    async fn stop(&mut self, key: &str) {
+        let (_, handle) = self.running.remove(key).unwrap();
+        handle.abort();
+        // wait for listeners to be dropped before they are bound again
+        handle.await.ok();
+        logger::info(format!("{} stopped", key)).log();
+    }
+
+    /// Start, stop or restart engines so running set matches `config`.
+    /// Returns false if some engine failed to start.
+    async fn apply(&mut self, config: Config) -> bool {
+        logger::configure(&config.log);
+        self.shutdown_timeout = Duration::from_secs(
+            config
+                .shutdown_timeout
+                .unwrap_or(config_loader::DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+        );
+        self.on_bind_error = config.on_bind_error.unwrap_or_default();
+        let mut wanted = engines(config);
+        let stale: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(k, (v, _))| wanted.get(*k) != Some(v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in stale {
+            self.stop(&key).await;
+        }
+        wanted.retain(|k, _| !self.running.contains_key(k));
+        let mut started = true;
+        for (key, engine) in wanted {
+            match start(&key, &engine) {
+                Ok(handle) => {
+                    logger::info(format!("{} started", key)).log();
+                    self.running.insert(key, (engine, handle));
+                }
+                Err(e) => {
+                    logger::error(format!("{} failed to start: {}", key, e)).log();
+                    started = false;
+                }
+            }
+        }
+        started
+    }
+
+    /// Apply `config`, shut down and exit if an engine failed to start
+    /// and policy says so
+    async fn apply_or_exit(&mut self, config: Config) {
+        if !self.apply(config).await && self.on_bind_error == BindErrorPolicy::Exit {
+            logger::error("stopping because an engine failed to start").log();
+            self.shutdown().await;
+            std::process::exit(1);
+        }
+    }
+
+    /// Stop accepting connections and wait for open sessions to finish
+    async fn shutdown(&mut self) {
+        let keys: Vec<String> = self.running.keys().cloned().collect();
+        for key in keys {
+            self.stop(&key).await;
+        }
+        session::stop();
+        let active = session::active();
+        if active > 0 {
+            logger::info(format!(
+                "waiting up to {}s for {} open sessions",
+                self.shutdown_timeout.as_secs(),
+                active
+            ))
+            .log();
+        }
+        let left = session::drain(self.shutdown_timeout).await;
+        if left > 0 {
+            logger::warn(format!(
+                "shutdown timeout expired, closing {} open sessions",
+                left
+            ))
+            .log();
+        }
+        logger::info("shutdown complete").log();
+    }
+}
+
+/// Start all engines from `config`, re-read `path` on SIGHUP and return
+/// after graceful shutdown on SIGTERM or SIGINT
+pub async fn spawn(config: Config, path: Option<String>) {
+    let mut signals = Signals::new();
+    let mut spawner = Spawner::new();
+    spawner.apply_or_exit(config).await;
+    loop {
+        match signals.recv().await {
+            Signal::Reload => {
+                let Some(path) = &path else {
+                    logger::warn("no configuration file to reload").log();
+                    continue;
+                };
+                logger::info(format!("reloading configuration from {}", path)).log();
+                match config_loader::load_config(path) {
+                    Ok(config) => spawner.apply_or_exit(config).await,
+                    Err(e) => {
+                        logger::error(format!("reload failed, keeping old configuration: {}", e))
+                            .log()
+                    }
+                }
+            }
+            Signal::ReopenLogs => {
+                logger::info("reopening access logs").log();
+                access_log::reopen_all();
+            }
+            Signal::Stats => {
+                logger::info(format!(
+                    "sessions={} buffer pool: {}",
+                    session::active(),
+                    buffer_pool::stats()
+                ))
+                .log();
+            }
+            Signal::Shutdown => {
+                logger::info("shutting down").log();
+                spawner.shutdown().await;
+                return;
+            }
+        }
     }
-    joins.shrink_to_fit();
-    ::futures::future::join_all(joins).await;
 }
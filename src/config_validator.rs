@@ -0,0 +1,282 @@
+//! Checks of configuration that can not be expressed by its types
+
+use crate::buffer_pool::{MAX_BUFFER_SIZE, MIN_BUFFER_SIZE};
+use crate::config_loader::{Config, ConfigError};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+
+/// Check parsed configuration for consistency and that files it names
+/// can be read, without touching network
+pub fn validate(config: &Config) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+    let engines = config.engine_commons();
+    for (key, common) in &engines {
+        if common.endpoints().is_empty() {
+            errors.push(format!("[{}]: no `port` or `listen` endpoints", key));
+        }
+        if let Some(size) = common.buffer_size {
+            if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&size) {
+                errors.push(format!(
+                    "[{}]: `buffer_size` {} is not in {}..={}",
+                    key, size, MIN_BUFFER_SIZE, MAX_BUFFER_SIZE
+                ));
+            }
+        }
+        let timeouts = [
+            ("handshake_timeout", common.handshake_timeout),
+            ("idle_timeout", common.idle_timeout),
+            ("max_lifetime", common.max_lifetime),
+        ];
+        for (option, value) in timeouts {
+            if value == Some(0) {
+                errors.push(format!("[{}]: `{}` must be positive", key, option));
+            }
+        }
+        let limits = [
+            ("max_connections", common.max_connections),
+            ("max_connections_per_ip", common.max_connections_per_ip),
+        ];
+        for (option, value) in limits {
+            if value == Some(0) {
+                errors.push(format!("[{}]: `{}` must be positive", key, option));
+            }
+        }
+        let client_acl = common.client_acl.as_deref().unwrap_or_default();
+        if let Some(rule) = client_acl.iter().find(|rule| rule.ports.is_some()) {
+            errors.push(format!(
+                "[{}]: `client_acl` rule \"{}\" can not have ports",
+                key, rule
+            ));
+        }
+        if let Some(throttle) = &common.throttle {
+            let groups = [
+                ("engine", throttle.engine),
+                ("client", throttle.client),
+                ("user", throttle.user),
+            ];
+            for (group, rates) in groups {
+                let rates = rates.unwrap_or_default();
+                for (direction, rate) in [("upload", rates.upload), ("download", rates.download)] {
+                    if rate == Some(0) {
+                        errors.push(format!(
+                            "[{}]: `throttle.{}.{}` must be positive",
+                            key, group, direction
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    // every endpoint is compared with endpoints of all following engines
+    for (i, (key, common)) in engines.iter().enumerate() {
+        for addr in common.endpoints() {
+            for (other_key, other) in &engines[i + 1..] {
+                if let Some(other_addr) = other.endpoints().iter().find(|a| a.overlaps(&addr)) {
+                    errors.push(format!(
+                        "[{}] and [{}]: endpoints {} and {} overlap",
+                        key, other_key, addr, other_addr
+                    ));
+                }
+            }
+        }
+    }
+    for (name, v) in &config.http {
+        if v.auth_schemes.as_ref().is_some_and(Vec::is_empty) {
+            errors.push(format!("[http.{}]: `auth_schemes` can not be empty", name));
+        }
+        let files = [
+            ("domain_blocklist", &v.domain_blocklist),
+            ("domain_allowlist", &v.domain_allowlist),
+            ("htpasswd", &v.htpasswd),
+        ];
+        check_files(&format!("http.{}", name), files, &mut errors);
+    }
+    for (name, v) in &config.socks5 {
+        let files = [
+            ("domain_blocklist", &v.domain_blocklist),
+            ("domain_allowlist", &v.domain_allowlist),
+            ("htpasswd", &v.htpasswd),
+        ];
+        check_files(&format!("socks5.{}", name), files, &mut errors);
+    }
+    for (name, v) in &config.tcppm {
+        let valid = v
+            .target
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            errors.push(format!(
+                "[tcppm.{}]: target \"{}\" is not in host:port format",
+                name, v.target
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+/// Check that list files of engine `key` can be read: a missing block
+/// list would let every host through
+fn check_files<const N: usize>(
+    key: &str,
+    files: [(&str, &Option<PathBuf>); N],
+    errors: &mut Vec<String>,
+) {
+    for (option, path) in files {
+        let Some(path) = path else {
+            continue;
+        };
+        if let Err(e) = fs::read(path) {
+            errors.push(format!(
+                "[{}]: can not read `{}` \"{}\": {}",
+                key,
+                option,
+                path.display(),
+                e
+            ));
+        }
+    }
+}
+
+/// Check that tcppm targets can be resolved. Blocks on DNS lookups.
+pub fn check_targets(config: &Config) -> Result<(), ConfigError> {
+    let errors: Vec<String> = config
+        .tcppm
+        .iter()
+        .filter_map(
+            |(name, v)| match v.target.to_socket_addrs().map(|mut a| a.next()) {
+                Ok(Some(_)) => None,
+                Ok(None) => Some(format!(
+                    "[tcppm.{}]: target \"{}\" has no addresses",
+                    name, v.target
+                )),
+                Err(e) => Some(format!(
+                    "[tcppm.{}]: can not resolve target \"{}\": {}",
+                    name, v.target, e
+                )),
+            },
+        )
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn overlapping_endpoints() {
+        let c: Config = toml::from_str(
+            r#"
+            [http.a]
+            port = 3128
+            [socks5.b]
+            listen = ["127.0.0.1:3128", "127.0.0.1:1080"]
+            [tcppm.c]
+            listen = "127.0.0.1:1080"
+            target = "localhost:80"
+            "#,
+        )
+        .unwrap();
+        let errors = validate(&c).unwrap_err();
+        assert_eq!(
+            errors.0,
+            [
+                "[http.a] and [socks5.b]: endpoints *:3128 and 127.0.0.1:3128 overlap",
+                "[socks5.b] and [tcppm.c]: endpoints 127.0.0.1:1080 and 127.0.0.1:1080 overlap"
+            ]
+        );
+    }
+    #[test]
+    fn invalid_target_and_missing_endpoints() {
+        let c: Config = toml::from_str(
+            r#"
+            [http.a]
+            [tcppm.c]
+            port = 1
+            target = "localhost"
+            "#,
+        )
+        .unwrap();
+        let errors = validate(&c).unwrap_err();
+        assert_eq!(
+            errors.0,
+            [
+                "[http.a]: no `port` or `listen` endpoints",
+                "[tcppm.c]: target \"localhost\" is not in host:port format"
+            ]
+        );
+    }
+    #[test]
+    fn buffer_size_range() {
+        let c: Config = toml::from_str(
+            r#"
+            [socks5.a]
+            port = 1080
+            buffer_size = 100
+            "#,
+        )
+        .unwrap();
+        let errors = validate(&c).unwrap_err();
+        assert_eq!(
+            errors.0,
+            ["[socks5.a]: `buffer_size` 100 is not in 512..=16777216"]
+        );
+    }
+    #[test]
+    fn client_rule_with_ports() {
+        let c: Config = toml::from_str(
+            r#"
+            [socks5.a]
+            port = 1080
+            client_acl = ["allow 10.0.0.0/8 port 1024-65535"]
+            target_acl = ["deny internal", "allow all port 443"]
+            "#,
+        )
+        .unwrap();
+        let errors = validate(&c).unwrap_err();
+        assert_eq!(
+            errors.0,
+            [concat!(
+                "[socks5.a]: `client_acl` rule \"allow 10.0.0.0/8 port 1024-65535\" ",
+                "can not have ports"
+            )]
+        );
+    }
+    #[test]
+    fn unreadable_files() {
+        let c: Config = toml::from_str(
+            r#"
+            [socks5.a]
+            port = 1080
+            domain_blocklist = "/nonexistent/blocked.txt"
+            htpasswd = "Cargo.toml"
+            "#,
+        )
+        .unwrap();
+        let errors = validate(&c).unwrap_err();
+        assert_eq!(errors.0.len(), 1);
+        assert!(errors.0[0].starts_with(
+            "[socks5.a]: can not read `domain_blocklist` \"/nonexistent/blocked.txt\": "
+        ));
+    }
+    #[test]
+    fn unresolvable_target() {
+        let c: Config = toml::from_str(
+            r#"
+            [tcppm.c]
+            port = 1
+            target = "127.0.0.1:80:70"
+            "#,
+        )
+        .unwrap();
+        assert!(check_targets(&c).is_err());
+    }
+}
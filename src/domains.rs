@@ -0,0 +1,188 @@
+//! Host name block and allow lists
+//!
+//! List files hold one pattern per line: `example.com` matches the name
+//! itself, `*.example.com` matches its subdomains. Empty lines and lines
+//! starting with `#` are skipped. Patterns are kept in a trie of labels
+//! from the top level domain down, so a lookup costs one step per label
+//! of the name however long the list is. Files are read again when their
+//! modification time changes.
+
+use crate::watched_file::{Content, WatchedFile};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    /// name ending here is in the list
+    exact: bool,
+    /// subdomains of name ending here are in the list
+    subdomains: bool,
+}
+
+/// Set of name patterns
+#[derive(Default)]
+pub struct DomainTrie {
+    root: Node,
+    len: usize,
+}
+
+fn labels(name: &str) -> impl Iterator<Item = &str> {
+    name.strip_suffix('.').unwrap_or(name).rsplit('.')
+}
+
+impl DomainTrie {
+    pub fn insert(&mut self, pattern: &str) -> Result<(), String> {
+        let pattern = pattern.to_ascii_lowercase();
+        let (name, subdomains) = match pattern.strip_prefix("*.") {
+            Some(name) => (name, true),
+            None => (pattern.as_str(), false),
+        };
+        if name.is_empty() || labels(name).any(|l| l.is_empty() || l.contains('*')) {
+            return Err(format!("invalid pattern \"{}\"", pattern));
+        }
+        let mut node = &mut self.root;
+        for label in labels(name) {
+            node = node.children.entry(label.into()).or_default();
+        }
+        match subdomains {
+            true => node.subdomains = true,
+            false => node.exact = true,
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let mut node = &self.root;
+        for label in labels(&host) {
+            if node.subdomains {
+                return true;
+            }
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.exact
+    }
+}
+
+impl Content for DomainTrie {
+    const KIND: &'static str = "domain list";
+
+    fn parse(text: &str) -> (DomainTrie, Vec<String>) {
+        let mut trie = DomainTrie::default();
+        let mut errors = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = trie.insert(line) {
+                errors.push(format!("line {}: {}", n + 1, e));
+            }
+        }
+        (trie, errors)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// List file, read again when it changes
+type DomainList = WatchedFile<DomainTrie>;
+
+/// Block and allow lists of an engine
+pub struct DomainFilter {
+    block: Option<DomainList>,
+    allow: Option<DomainList>,
+}
+
+impl DomainFilter {
+    pub fn new(block: Option<&Path>, allow: Option<&Path>) -> DomainFilter {
+        DomainFilter {
+            block: block.map(DomainList::new),
+            allow: allow.map(DomainList::new),
+        }
+    }
+
+    /// Whether `host` is not in the block list and, if there is an allow
+    /// list, is in it
+    pub fn allows(&self, host: &str) -> bool {
+        self.block
+            .as_ref()
+            .is_none_or(|list| !list.current().matches(host))
+            && self
+                .allow
+                .as_ref()
+                .is_none_or(|list| list.current().matches(host))
+    }
+
+    /// Whether target address `ip` is let through: the block list holds
+    /// names only, an allow list must hold the address as written by
+    /// [`IpAddr`]'s `Display`
+    pub fn allows_address(&self, ip: IpAddr) -> bool {
+        self.allow
+            .as_ref()
+            .is_none_or(|list| list.current().matches(&ip.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+    #[test]
+    fn patterns() {
+        let (trie, errors) =
+            DomainTrie::parse("# ads\nexample.com\n*.Ads.example.net\n\n*.\nbad..name\n");
+        assert_eq!(trie.len(), 2);
+        assert_eq!(errors.len(), 2);
+        assert!(trie.matches("example.com"));
+        assert!(trie.matches("EXAMPLE.com."));
+        assert!(!trie.matches("www.example.com"));
+        assert!(!trie.matches("com"));
+        assert!(trie.matches("x.ads.example.net"));
+        assert!(trie.matches("a.b.ads.example.net"));
+        assert!(!trie.matches("ads.example.net"));
+        assert!(!trie.matches("example.net"));
+    }
+    #[test]
+    fn reload_on_change() {
+        let path = std::env::temp_dir().join(format!("domains-{}.txt", std::process::id()));
+        fs::write(&path, "blocked.example\n").unwrap();
+        let filter = DomainFilter::new(Some(&path), None);
+        assert!(!filter.allows("blocked.example"));
+        assert!(filter.allows("other.example"));
+        fs::write(&path, "other.example\n").unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(5);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        filter.block.as_ref().unwrap().check();
+        assert!(filter.allows("blocked.example"));
+        assert!(!filter.allows("other.example"));
+        fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn addresses() {
+        let path = std::env::temp_dir().join(format!("allowed-{}.txt", std::process::id()));
+        fs::write(&path, "example.com\n10.0.0.1\n2001:DB8::1\n").unwrap();
+        let filter = DomainFilter::new(None, Some(&path));
+        assert!(filter.allows_address("10.0.0.1".parse().unwrap()));
+        assert!(filter.allows_address("2001:db8::1".parse().unwrap()));
+        assert!(!filter.allows_address("10.0.0.2".parse().unwrap()));
+        assert!(!filter.allows_address("::1".parse().unwrap()));
+        let open = DomainFilter::new(Some(&path), None);
+        assert!(open.allows_address("10.0.0.1".parse().unwrap()));
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,409 @@
+//! Proxy authentication schemes
+//!
+//! Digest (RFC 7616) is supported with MD5 and SHA-256 and `qop=auth`.
+//! Nonces are random, expire after `NONCE_LIFETIME` and every nonce count
+//! is accepted once, so a captured request can not be sent again.
+
+use super::header_value_parser::kv;
+use crate::logger;
+use crate::users::{same, Users};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use md5::Md5;
+use sha2::{Digest as _, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a Digest nonce may be used
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+/// Unused nonces kept at most, the oldest are dropped to make room.
+/// Used ones are kept apart, so a flood of challenges can not push out
+/// nonces of authenticated clients.
+const MAX_NONCES: usize = 10_000;
+/// How far below the highest one a nonce count may arrive
+const NC_WINDOW: u32 = 64;
+
+/// Realm as a quoted string can hold it
+fn quoted(realm: &str) -> String {
+    realm.replace(['\\', '"'], "")
+}
+
+/// Whether `digest_uri` of credentials names request target `uri`, some
+/// clients send only the path of absolute URLs
+fn same_target(digest_uri: &str, uri: &str) -> bool {
+    let path = uri
+        .split_once("://")
+        .map(|(_, rest)| rest.find('/').map_or("/", |i| &rest[i..]));
+    digest_uri == uri || path == Some(digest_uri)
+}
+
+/// `Proxy-Authenticate` value asking for Basic credentials
+pub fn basic_challenge(realm: &str) -> String {
+    format!("Basic realm=\"{}\", charset=\"UTF-8\"", quoted(realm))
+}
+
+/// User and password of Basic `Proxy-Authorization` value
+pub fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = BASE64_STANDARD.decode(encoded.trim()).ok()?;
+    let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Algorithm> {
+        match name.to_ascii_uppercase().as_str() {
+            "MD5" => Some(Algorithm::Md5),
+            "SHA-256" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    /// Lowercase hex digest of `data`
+    fn hash(&self, data: &str) -> String {
+        match self {
+            Algorithm::Md5 => format!("{:x}", Md5::digest(data.as_bytes())),
+            Algorithm::Sha256 => format!("{:x}", Sha256::digest(data.as_bytes())),
+        }
+    }
+}
+
+/// Issued nonce and the nonce counts it was used with
+struct Nonce {
+    issued: Instant,
+    highest: u32,
+    /// bit `n` is set if count `highest - n` was used
+    seen: u64,
+}
+
+impl Nonce {
+    /// Record use with count `nc`, false if it was used before
+    fn accept(&mut self, nc: u32) -> bool {
+        if nc > self.highest {
+            let shift = nc - self.highest;
+            self.seen = if shift >= NC_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = nc;
+            return true;
+        }
+        let bit = match self.highest - nc {
+            offset if offset < NC_WINDOW => 1u64 << offset,
+            _ => return false,
+        };
+        let fresh = self.seen & bit == 0;
+        self.seen |= bit;
+        fresh && nc > 0
+    }
+}
+
+/// Issued nonces with queues in the order they were issued and first
+/// used, so expired ones are dropped from the front
+#[derive(Default)]
+struct Nonces {
+    states: HashMap<String, Nonce>,
+    /// nonces not used when queued, entries used since are skipped
+    unused: VecDeque<String>,
+    used: VecDeque<String>,
+}
+
+impl Nonces {
+    fn expired(&self, nonce: &str) -> bool {
+        self.states
+            .get(nonce)
+            .is_none_or(|n| n.issued.elapsed() >= NONCE_LIFETIME)
+    }
+
+    /// Drop the oldest unused nonce, false if there is none
+    fn pop_unused(&mut self) -> bool {
+        let Some(nonce) = self.unused.pop_front() else {
+            return false;
+        };
+        if self.states.get(&nonce).is_some_and(|n| n.highest == 0) {
+            self.states.remove(&nonce);
+        }
+        true
+    }
+
+    fn pop_used(&mut self) {
+        if let Some(nonce) = self.used.pop_front() {
+            self.states.remove(&nonce);
+        }
+    }
+
+    /// Drop expired nonces and make room for a new one
+    fn prune(&mut self) {
+        while self.unused.front().is_some_and(|n| self.expired(n)) {
+            self.pop_unused();
+        }
+        while self.used.front().is_some_and(|n| self.expired(n)) {
+            self.pop_used();
+        }
+        while self.unused.len() >= MAX_NONCES && self.pop_unused() {}
+    }
+
+    fn insert(&mut self, nonce: String) {
+        self.prune();
+        let state = Nonce {
+            issued: Instant::now(),
+            highest: 0,
+            seen: 0,
+        };
+        self.states.insert(nonce.clone(), state);
+        self.unused.push_back(nonce);
+    }
+
+    /// Record use of `nonce` with count `nc`
+    fn accept(&mut self, nonce: &str, nc: u32) -> Result<(), DigestError> {
+        let state = match self.states.get_mut(nonce) {
+            Some(state) if state.issued.elapsed() < NONCE_LIFETIME => state,
+            _ => return Err(DigestError::Stale),
+        };
+        let first = state.highest == 0;
+        if !state.accept(nc) {
+            return Err(DigestError::Replayed);
+        }
+        if first {
+            self.used.push_back(nonce.to_string());
+            if self.used.len() > MAX_NONCES {
+                self.pop_used();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DigestError {
+    Invalid,
+    /// nonce is unknown or expired, client should retry with a new one
+    Stale,
+    /// nonce count was used before
+    Replayed,
+}
+
+/// Digest scheme of an engine with nonces it issued
+pub struct Digest {
+    realm: String,
+    nonces: Mutex<Nonces>,
+}
+
+impl Digest {
+    pub fn new(realm: &str) -> Digest {
+        Digest {
+            realm: quoted(realm),
+            nonces: Mutex::default(),
+        }
+    }
+
+    /// New random nonce, `None` if no random bytes could be had
+    fn new_nonce(&self) -> Option<String> {
+        let mut bytes = [0u8; 16];
+        if let Err(e) = getrandom::getrandom(&mut bytes) {
+            logger::error(format!("can not make Digest nonce: {}", e)).log();
+            return None;
+        }
+        let nonce: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.nonces.lock().unwrap().insert(nonce.clone());
+        Some(nonce)
+    }
+
+    /// `Proxy-Authenticate` values asking for Digest credentials with a
+    /// new nonce, SHA-256 first as clients take the first they support.
+    /// None are made without a random nonce.
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        let Some(nonce) = self.new_nonce() else {
+            return Vec::new();
+        };
+        [Algorithm::Sha256, Algorithm::Md5]
+            .iter()
+            .map(|algorithm| {
+                format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+                    self.realm,
+                    algorithm.name(),
+                    nonce,
+                    if stale { ", stale=true" } else { "" }
+                )
+            })
+            .collect()
+    }
+
+    /// Check Digest `credentials` (the header value without scheme) of a
+    /// request with `method` and `uri`, returns the user
+    pub fn verify(
+        &self,
+        users: &Users,
+        method: &str,
+        uri: &str,
+        credentials: &str,
+    ) -> Result<String, DigestError> {
+        let (_, params) = kv(credentials).or(Err(DigestError::Invalid))?;
+        let param = |name: &str| {
+            params
+                .get(name)
+                .map(String::as_str)
+                .ok_or(DigestError::Invalid)
+        };
+        let user = param("username")?;
+        let algorithm = params.get("algorithm").map_or("MD5", String::as_str);
+        let algorithm = Algorithm::parse(algorithm).ok_or(DigestError::Invalid)?;
+        let matches = param("realm")? == self.realm
+            && same_target(param("uri")?, uri)
+            && param("qop")? == "auth"
+            && params.get("userhash").is_none_or(|v| v == "false");
+        if !matches {
+            return Err(DigestError::Invalid);
+        }
+        let (nonce, nc, cnonce) = (param("nonce")?, param("nc")?, param("cnonce")?);
+        let count = u32::from_str_radix(nc, 16).or(Err(DigestError::Invalid))?;
+        let password = users.password(user).ok_or(DigestError::Invalid)?;
+        let ha1 = algorithm.hash(&format!("{}:{}:{}", user, self.realm, password));
+        let ha2 = algorithm.hash(&format!("{}:{}", method, param("uri")?));
+        let expected = algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+        if !same(
+            expected.as_bytes(),
+            param("response")?.to_ascii_lowercase().as_bytes(),
+        ) {
+            return Err(DigestError::Invalid);
+        }
+        self.nonces.lock().unwrap().accept(nonce, count)?;
+        Ok(user.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    #[test]
+    fn basic() {
+        let credentials = basic_credentials("Basic YWxpY2U6b3Blbjpz ZXNhbWU=");
+        assert_eq!(credentials, None);
+        let credentials = basic_credentials("basic YWxpY2U6b3BlbjpzZXNhbWU=");
+        assert_eq!(credentials, Some(("alice".into(), "open:sesame".into())));
+        assert_eq!(basic_credentials("Digest username=\"alice\""), None);
+        assert_eq!(
+            basic_challenge("a \"b\""),
+            "Basic realm=\"a b\", charset=\"UTF-8\""
+        );
+    }
+    #[test]
+    fn target() {
+        assert!(same_target("example.com:443", "example.com:443"));
+        assert!(same_target("http://a.example/x?y", "http://a.example/x?y"));
+        assert!(same_target("/x?y", "http://a.example/x?y"));
+        assert!(same_target("/", "http://a.example"));
+        assert!(!same_target("/z", "http://a.example/x"));
+        assert!(!same_target("/", "example.com:443"));
+    }
+    #[test]
+    fn nonce_counts() {
+        let mut nonce = Nonce {
+            issued: Instant::now(),
+            highest: 0,
+            seen: 0,
+        };
+        assert!(!nonce.accept(0));
+        assert!(nonce.accept(1));
+        assert!(nonce.accept(3));
+        assert!(!nonce.accept(3));
+        assert!(nonce.accept(2));
+        assert!(!nonce.accept(1));
+        assert!(nonce.accept(100));
+        assert!(!nonce.accept(3));
+    }
+    #[test]
+    fn digest() {
+        // example of RFC 7616 section 3.9.1
+        let listed = BTreeMap::from([("Mufasa".to_string(), "Circle of Life".to_string())]);
+        let users = Users::new(Some(&listed), None).unwrap();
+        let credentials = |algorithm: &str, nc: &str, response: &str| {
+            format!(
+                concat!(
+                    r#"username="Mufasa", realm="http-auth@example.org", "#,
+                    r#"uri="/dir/index.html", algorithm={}, nc={}, "#,
+                    r#"nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", qop=auth, "#,
+                    r#"cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", "#,
+                    r#"response="{}", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#
+                ),
+                algorithm, nc, response
+            )
+        };
+        let md5 = credentials("MD5", "00000001", "8ca523f5e9506fed4657c9700eebdbec");
+        let sha256 = credentials(
+            "SHA-256",
+            "00000001",
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1",
+        );
+        for first in [&md5, &sha256] {
+            let digest = Digest::new("http-auth@example.org");
+            let verify = |c: &str| digest.verify(&users, "GET", "/dir/index.html", c);
+            assert_eq!(verify(first), Err(DigestError::Stale));
+            let issued = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_string();
+            digest.nonces.lock().unwrap().insert(issued);
+            assert_eq!(verify(first), Ok("Mufasa".to_string()));
+            assert_eq!(verify(first), Err(DigestError::Replayed));
+            let uri = digest.verify(&users, "GET", "/dir/other.html", first);
+            assert_eq!(uri, Err(DigestError::Invalid));
+        }
+        let digest = Digest::new("http-auth@example.org");
+        let wrong = credentials("MD5", "00000002", "8ca523f5e9506fed4657c9700eebdbec");
+        let verify = digest.verify(&users, "GET", "/dir/index.html", &wrong);
+        assert_eq!(verify, Err(DigestError::Invalid));
+        let challenges = digest.challenges(true);
+        let (_, sha256) = kv(challenges[0].strip_prefix("Digest ").unwrap()).unwrap();
+        let (_, md5) = kv(challenges[1].strip_prefix("Digest ").unwrap()).unwrap();
+        assert_eq!(
+            (sha256["algorithm"].as_str(), md5["algorithm"].as_str()),
+            ("SHA-256", "MD5")
+        );
+        assert_eq!(sha256["nonce"], md5["nonce"]);
+        assert_eq!(sha256["stale"], "true");
+        assert!(digest
+            .nonces
+            .lock()
+            .unwrap()
+            .states
+            .contains_key(&md5["nonce"]));
+    }
+    #[test]
+    fn nonce_flood() {
+        let mut nonces = Nonces::default();
+        nonces.insert("used".to_string());
+        nonces.insert("waiting".to_string());
+        assert_eq!(nonces.accept("used", 1), Ok(()));
+        for n in 0..MAX_NONCES {
+            nonces.insert(n.to_string());
+        }
+        assert_eq!(nonces.unused.len(), MAX_NONCES);
+        assert_eq!(nonces.states.len(), MAX_NONCES + 1);
+        assert_eq!(nonces.accept("used", 2), Ok(()));
+        assert_eq!(nonces.accept("waiting", 1), Err(DigestError::Stale));
+        assert_eq!(nonces.accept("0", 1), Ok(()));
+        nonces.insert("new".to_string());
+        nonces.insert("newer".to_string());
+        assert!(nonces.states.contains_key("0"));
+        assert!(!nonces.states.contains_key("1"));
+        assert!(nonces.states.contains_key("2"));
+    }
+}
@@ -1,6 +1,6 @@
+use crate::acl::{self, ConnectError, Rule};
 use std::ops::{Deref, DerefMut};
 use std::sync::Mutex;
-use tokio::io::Result;
 use tokio::net::TcpStream;
 use lru_cache::LruCache;
 
@@ -47,13 +47,14 @@ impl ConnectionPool {
     pub async fn connect_or_reuse<'cp>(
         &'cp mut self,
         domain_port: &String,
-    ) -> Result<SockRef<'cp>> {
+        target_acl: &[Rule],
+    ) -> Result<SockRef<'cp>, ConnectError> {
         let temp = self.connections.lock().unwrap().remove(domain_port);
         let sock = if let Some(sock) = temp {
             sock
         } else {
-            let sock = TcpStream::connect(domain_port).await?;
-            sock.set_nodelay(true)?;
+            let sock = acl::connect(target_acl, domain_port.as_str()).await?;
+            sock.set_nodelay(true).ok();
             sock
         };
         Ok(SockRef {
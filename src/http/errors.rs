@@ -1,3 +1,5 @@
+use crate::util::Timeout;
+
 #[derive(Debug)]
 pub enum HttpError {
     HeaderToBig,
@@ -7,6 +9,12 @@ pub enum HttpError {
     ResponceHeaderParseError,
     UrlProtocolInvalid,
     TargetUnreachable(String),
+    TargetDenied(String),
+    DomainDenied(String),
+    AuthRequired,
+    AuthFailed,
+    NonceStale,
+    NonceReplayed,
     LimitedTranciever,
     LimitedTrancieverRead,
     LimitedTrancieverWrite,
@@ -14,5 +22,36 @@ pub enum HttpError {
     LineTooLong,
     LineNotUtf8,
     ChunkTranciever,
+    Timeout(Timeout),
     Internal
-}
\ No newline at end of file
+}
+
+impl HttpError {
+    /// Kind of a failed request or connection
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HttpError::HeaderToBig => "header_too_big",
+            HttpError::HeaderIncomplete => "header_incomplete",
+            HttpError::HeaderNotUtf8 => "header_not_utf8",
+            HttpError::HeaderParseError => "header_invalid",
+            HttpError::ResponceHeaderParseError => "response_header_invalid",
+            HttpError::UrlProtocolInvalid => "url_protocol_invalid",
+            HttpError::TargetUnreachable(_) => "target_unreachable",
+            HttpError::TargetDenied(_) => "target_denied",
+            HttpError::DomainDenied(_) => "domain_denied",
+            HttpError::AuthRequired => "auth_required",
+            HttpError::AuthFailed => "auth_failed",
+            HttpError::NonceStale => "nonce_stale",
+            HttpError::NonceReplayed => "nonce_replayed",
+            HttpError::LimitedTranciever
+            | HttpError::LimitedTrancieverRead
+            | HttpError::LimitedTrancieverWrite
+            | HttpError::ChunkTranciever => "transceiver",
+            HttpError::LineRead => "line_read",
+            HttpError::LineTooLong => "line_too_long",
+            HttpError::LineNotUtf8 => "line_not_utf8",
+            HttpError::Timeout(timeout) => timeout.kind(),
+            HttpError::Internal => "internal",
+        }
+    }
+}
@@ -1,17 +1,46 @@
-use nom::{IResult, branch::alt, bytes::complete::{escaped, is_not, tag}, character::complete::{multispace0, multispace1, none_of, one_of}, combinator::{recognize, rest}, multi::separated_list0, sequence::{delimited, separated_pair}};
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped, is_not, tag},
+    character::complete::{anychar, multispace0, multispace1, none_of},
+    combinator::{opt, recognize, rest},
+    multi::{many1, separated_list0},
+    sequence::{delimited, separated_pair},
+    IResult,
+};
 use std::collections::BTreeMap;
 
-fn literal(input: &str) -> IResult<&str, &str> {
-    alt((
-        is_not(" \","),
-        recognize(delimited(
-            tag("\""),
-            escaped(none_of("\\\""), '\\', one_of("\"\\")),
-            tag("\""),
-        )),
+fn quoted(input: &str) -> IResult<&str, &str> {
+    recognize(delimited(
+        tag("\""),
+        opt(escaped(none_of("\\\""), '\\', anychar)),
+        tag("\""),
     ))(input)
 }
 
+/// Token, quoted string or both joined like `name="quoted value"`
+fn literal(input: &str) -> IResult<&str, &str> {
+    recognize(many1(alt((is_not(" \","), quoted))))(input)
+}
+
+/// Content of quoted string with escapes resolved, other values as is
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut result = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                result.push(if c == '\\' {
+                    chars.next().unwrap_or(c)
+                } else {
+                    c
+                });
+            }
+            result
+        }
+        None => value.to_string(),
+    }
+}
+
 fn spaced_item(input: &str) -> IResult<&str, &str> {
     delimited(
         multispace0,
@@ -24,7 +53,9 @@ pub fn value_list(input: &str) -> IResult<&str, Vec<&str>> {
     separated_list0(tag(","), spaced_item)(input)
 }
 
-pub fn kv(input: &str) -> IResult<&str, BTreeMap<&str, &str>> {
+/// Parameters like `a=1, b="two, three"`. Names are lowercased, quoted
+/// values unquoted.
+pub fn kv(input: &str) -> IResult<&str, BTreeMap<String, String>> {
     fn kv(input: &str) -> IResult<&str, (&str, &str)> {
         separated_pair(is_not("="), tag("="), rest)(input)
     }
@@ -32,7 +63,7 @@ pub fn kv(input: &str) -> IResult<&str, BTreeMap<&str, &str>> {
     let mut result = BTreeMap::new();
     for item in items {
         let (_, (k, v)) = kv(item)?;
-        result.insert(k, v);
+        result.insert(k.trim().to_ascii_lowercase(), unquote(v.trim()));
     }
     Ok((input, result))
 }
@@ -63,4 +94,13 @@ mod test {
         assert_eq!(kv["b"], "2");
         assert_eq!(kv["c"], "3");
     }
+    #[test]
+    fn kv_quoted() {
+        let (rest, kv) = kv(r#"Username="a \"b\", c", realm="", qop=auth, URI="/x?a=b""#).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(kv["username"], r#"a "b", c"#);
+        assert_eq!(kv["realm"], "");
+        assert_eq!(kv["qop"], "auth");
+        assert_eq!(kv["uri"], "/x?a=b");
+    }
 }
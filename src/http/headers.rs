@@ -14,23 +14,19 @@ impl Headers {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        let mut result = String::new();
-        for header in &self.headers {
-            result += format!("{}: {}\r\n", header.0, header.1).as_str();
-        }
-        result
-    }
-
-    /// Combine all header with the same name in one value.
+    /// Combine all header with the same name (case insensitive) in one value.
     pub fn combined_value<S: AsRef<str>>(&self, key: S) -> Option<String> {
-        let key = key.as_ref().to_string();
+        let key = key.as_ref();
         let mut result = String::new();
-        for (_k, v) in self.headers.iter().filter(|(k, _v)| *k == key) {
+        for (_k, v) in self
+            .headers
+            .iter()
+            .filter(|(k, _v)| k.eq_ignore_ascii_case(key))
+        {
             result += v;
             result += ", ";
         }
-        if result.len() > 0 {
+        if !result.is_empty() {
             result = result[..result.len() - 2].to_string();
             Some(result)
         } else {
@@ -46,6 +42,21 @@ impl Headers {
         self.headers
             .push((key.as_ref().to_string(), value.as_ref().to_string()))
     }
+
+    /// Remove all headers with the name (case insensitive)
+    pub fn remove_header<S: AsRef<str>>(&mut self, key: S) {
+        let key = key.as_ref();
+        self.headers.retain(|(k, _v)| !k.eq_ignore_ascii_case(key))
+    }
+}
+
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for header in &self.headers {
+            write!(f, "{}: {}\r\n", header.0, header.1)?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Debug for Headers {
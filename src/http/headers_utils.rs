@@ -1,10 +1,8 @@
-use super::header_value_parser::{value_list, kv};
+use super::header_value_parser::{kv, value_list};
 
 impl super::headers::Headers {
     pub fn is_chuncked(&self) -> bool {
-        let te = self
-            .combined_value("Transfer-Encoding")
-            .unwrap_or(String::new());
+        let te = self.combined_value("Transfer-Encoding").unwrap_or_default();
         if let Ok(("", list)) = value_list(te.as_str()) {
             list.contains(&"chunked")
         } else {
@@ -12,19 +10,17 @@ impl super::headers::Headers {
         }
     }
     pub fn content_length(&self) -> Option<usize> {
-        let cl = self
-            .combined_value("Content-Length")
-            .unwrap_or(String::new());
+        let cl = self.combined_value("Content-Length").unwrap_or_default();
         cl.parse().ok()
     }
 
     pub fn is_keep_alive(&self) -> bool {
-        let c = self.combined_value("Connection").unwrap_or(String::new());
+        let c = self.combined_value("Connection").unwrap_or_default();
         c.to_lowercase() == "keep-alive"
     }
 
     pub fn keep_alive_value(&self) -> Option<KeepAlive> {
-        let kav = self.combined_value("Keep-Alive").unwrap_or(String::new());
+        let kav = self.combined_value("Keep-Alive").unwrap_or_default();
         let (_, kav) = kv(kav.as_str()).ok()?;
         let timeout = kav.get("timeout")?.parse().ok()?;
         let max = kav.get("max")?.parse().ok()?;
@@ -33,7 +29,8 @@ impl super::headers::Headers {
 }
 
 #[derive(Debug, Clone)]
-pub struct KeepAlive{
+pub struct KeepAlive {
     pub timeout: u64,
-    pub max: u64
-}
\ No newline at end of file
+    #[allow(dead_code)]
+    pub max: u64,
+}
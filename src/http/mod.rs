@@ -1,91 +1,318 @@
-use super::util;
-use crate::config_loader::HttpConfig;
+use super::util::{self, RelayOptions, Timeout};
+use crate::acl::{self, ConnectError, Ports, Rule};
+use crate::admission::{self, Admission, Permit, Refusal};
+use crate::buffer_pool;
+use crate::config_loader::{AuthScheme, HttpConfig};
+use crate::domains::DomainFilter;
+use crate::listener::{self, Listener, PeerAddr, Stream};
 use crate::logger;
-use std::time::Duration;
-use tokio;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::session;
+use crate::throttle::{Limiter, Throttle, Throttles};
+use crate::users::{Snapshot, Users};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind};
 use tokio::net::TcpStream;
 use tokio_io_timeout::TimeoutStream;
 
 pub mod errors;
+use auth::{Digest, DigestError};
 use errors::HttpError;
 use headers::Headers;
 use response::Response;
+mod auth;
 mod connection_pool;
 mod header_value_parser;
 mod headers;
 mod headers_utils;
-pub(self) mod parser;
+mod parser;
 mod request;
 mod response;
 
 const INITIAL_HEADER_CAPACITY: usize = 512;
 const MAX_HEADER_HEADER_CAPACITY: usize = 64 * 1024;
 const MAX_LINE_SIZE: usize = 1024;
-const DEFAULT_TIMEOUT_SECS: u64 = 120;
 const TIMEOUT_TOLERANCE_SECS: u64 = 10;
+const DEFAULT_REALM: &str = "proxy";
 type HttpResult<T> = Result<T, HttpError>;
 const ERROR_400: &str = std::include_str!("error_pages/400.html");
+const ERROR_403: &str = std::include_str!("error_pages/403.html");
+const ERROR_407: &str = std::include_str!("error_pages/407.html");
 const ERROR_502: &str = std::include_str!("error_pages/502.html");
+const ERROR_503: &str = std::include_str!("error_pages/503.html");
+
+/// Host of CONNECT `authority`, without port and IPv6 brackets
+fn authority_host(authority: &str) -> &str {
+    let host = authority
+        .rsplit_once(':')
+        .map_or(authority, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Response to a client refused right after accept
+fn refusal_reply(refusal: Refusal) -> Vec<u8> {
+    let (status, reason, page) = match refusal {
+        Refusal::ClientDenied => (403, "forbidden", ERROR_403),
+        _ => (503, "service unavailable", ERROR_503),
+    };
+    let mut headers = Headers::new();
+    headers.insert_header("Content-Length", page.len().to_string());
+    headers.insert_header("Content-Type", "text/html");
+    headers.insert_header("Connection", "close");
+    let response = Response::new("1.1", status, reason, headers);
+    format!("{}{}", response, page).into_bytes()
+}
+
+/// Established CONNECT tunnel, relayed outside the request loop
+struct Tunnel {
+    client: Pin<Box<TimeoutStream<Stream>>>,
+    target: TcpStream,
+    /// access record, logged when the tunnel ends
+    event: logger::Event,
+    throttles: Throttles,
+}
+
+/// Target rules, host lists and authentication of an HTTP engine
+struct Policy {
+    acl: Arc<[Rule]>,
+    connect_ports: Option<Ports>,
+    domains: DomainFilter,
+    /// users of proxy authentication, `None` if it is off
+    users: Option<Users>,
+    realm: String,
+    /// whether Basic scheme is accepted
+    basic: bool,
+    /// nonces of Digest scheme, `None` if it is not accepted
+    digest: Option<Digest>,
+}
+
+impl Policy {
+    /// Whether host name lists let `host` through, addresses only have to
+    /// be in the allow list if there is one
+    fn allows_host(&self, host: &str) -> bool {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        match literal.parse::<IpAddr>() {
+            Ok(ip) => self.domains.allows_address(ip),
+            Err(_) => self.domains.allows(host),
+        }
+    }
+
+    /// Whether CONNECT to `authority` uses an allowed port
+    fn allows_connect(&self, authority: &str) -> bool {
+        let port = authority
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok());
+        self.connect_ports
+            .as_ref()
+            .is_none_or(|ports| port.is_some_and(|port| ports.contains(port)))
+    }
+
+    /// `Proxy-Authenticate` values of accepted schemes, strongest first
+    fn challenges(&self, stale: bool) -> Vec<String> {
+        let mut challenges = self
+            .digest
+            .as_ref()
+            .map_or(Vec::new(), |d| d.challenges(stale));
+        if self.basic {
+            challenges.push(auth::basic_challenge(&self.realm));
+        }
+        challenges
+    }
+}
 
 pub struct Http {
     name: String,
     config: HttpConfig,
+    limiter: Arc<Limiter>,
+    admission: Admission,
+    policy: Arc<Policy>,
 }
 
 impl Http {
-    pub fn new(name: &String, config: &HttpConfig) -> Http {
+    pub fn new(name: &str, config: &HttpConfig) -> Http {
+        let realm = config
+            .auth_realm
+            .clone()
+            .unwrap_or_else(|| DEFAULT_REALM.to_string());
+        let schemes = config
+            .auth_schemes
+            .as_deref()
+            .unwrap_or(&[AuthScheme::Basic]);
         Http {
-            name: name.clone(),
+            name: name.to_string(),
             config: config.clone(),
+            limiter: Arc::new(Limiter::new(config.common.throttle.as_ref())),
+            admission: Admission::new(&config.common),
+            policy: Arc::new(Policy {
+                acl: config.common.target_rules(),
+                connect_ports: config.connect_ports.clone(),
+                domains: DomainFilter::new(
+                    config.domain_blocklist.as_deref(),
+                    config.domain_allowlist.as_deref(),
+                ),
+                users: Users::new(config.users.as_ref(), config.htpasswd.as_deref()),
+                basic: schemes.contains(&AuthScheme::Basic),
+                digest: schemes
+                    .contains(&AuthScheme::Digest)
+                    .then(|| Digest::new(&realm)),
+                realm,
+            }),
         }
     }
 
-    pub async fn serve(&self) {
-        let listener = util::bind_listener(self.config.port).await;
-        loop {
-            let (sock, _addr) = listener.accept().await.unwrap();
+    pub async fn serve(&self, listeners: Vec<Listener>) {
+        listener::accept_all(("http", &self.name), listeners, |sock, addr| {
             let name_clone = self.name.clone();
-            tokio::spawn(async move { Self::http_processor(name_clone, sock).await });
-        }
+            let relay = self.config.common.relay_options();
+            let limiter = self.limiter.clone();
+            let permit = match self.admission.admit(addr.ip()) {
+                Ok(permit) => permit,
+                Err(refusal) => {
+                    let reply = refusal_reply(refusal);
+                    admission::refuse(("http", &self.name), sock, &addr, refusal, &reply);
+                    return None;
+                }
+            };
+            let policy = self.policy.clone();
+            Some(Self::http_processor(
+                name_clone, sock, addr, relay, limiter, permit, policy,
+            ))
+        })
+        .await;
     }
 
-    async fn http_processor(name: String, sock: TcpStream) {
-        match Self::http_parser(name, sock).await {
+    async fn http_processor(
+        name: String,
+        sock: Stream,
+        addr: PeerAddr,
+        relay: RelayOptions,
+        limiter: Arc<Limiter>,
+        permit: Permit,
+        policy: Arc<Policy>,
+    ) {
+        let _permit = permit;
+        let started = Instant::now();
+        // a tunnel gets what is left of the lifetime from relay, which logs
+        // it however it ends
+        let session = Self::http_parser(&name, sock, relay, &limiter, &policy);
+        let result = match relay.max_lifetime {
+            Some(lifetime) => tokio::time::timeout(lifetime, session)
+                .await
+                .unwrap_or(Err(HttpError::Timeout(Timeout::Lifetime))),
+            None => session.await,
+        };
+        let result = match result {
+            Ok(Some(tunnel)) => {
+                let lifetime = relay
+                    .max_lifetime
+                    .map(|l| l.saturating_sub(started.elapsed()));
+                let relay = RelayOptions {
+                    max_lifetime: lifetime,
+                    ..relay
+                };
+                Self::relay_tunnel(tunnel, relay).await
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
             Ok(_) => (),
             Err(e) => {
-                logger::log(format!("client error: {:?}", e));
+                let mut event = logger::warn("client error")
+                    .engine("http", &name)
+                    .client(&addr)
+                    .error(e.kind());
+                if let HttpError::TargetUnreachable(target)
+                | HttpError::TargetDenied(target)
+                | HttpError::DomainDenied(target) = &e
+                {
+                    event = event.target(target);
+                }
+                event.log();
+            }
+        }
+    }
+    /// Move data of an established CONNECT tunnel until it ends and log it
+    async fn relay_tunnel(tunnel: Tunnel, relay: RelayOptions) -> HttpResult<()> {
+        let Tunnel {
+            client: mut timed_out_stream,
+            target: dst_sock,
+            event,
+            throttles,
+        } = tunnel;
+        let spliced = if throttles.is_empty() {
+            util::try_splice(relay, timed_out_stream.get_ref(), &dst_sock).await
+        } else {
+            None
+        };
+        let transfer = match spliced {
+            Some(transfer) => transfer,
+            None => {
+                // idle tunnel is detected by relay, it may be silent
+                // in one direction while data moves in the other
+                timed_out_stream.as_mut().set_read_timeout_pinned(None);
+                let mut dst_sock = dst_sock;
+                util::transceiver(&mut timed_out_stream, &mut dst_sock, relay, &throttles).await
             }
+        };
+        event.transfer(&transfer).log();
+        if let Some(timeout) = transfer.timeout {
+            return Err(HttpError::Timeout(timeout));
+        } else if transfer.error.is_some() {
+            return Err(HttpError::LimitedTranciever);
         }
+        Ok(())
     }
-    async fn limited_transceiver<R, W>(src: &mut W, dst: &mut R, mut limit: usize) -> HttpResult<()>
+
+    /// Copy up to `limit` bytes through a pooled buffer of `buffer_size`
+    /// as fast as `throttle` allows, return number of bytes copied
+    async fn limited_transceiver<R, W>(
+        src: &mut W,
+        dst: &mut R,
+        mut limit: usize,
+        buffer_size: usize,
+        throttle: &Throttle,
+    ) -> HttpResult<usize>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
-        let mut dst_buf = [0u8; 2000];
+        // full size even for short bodies, the pool reuses only equal sizes
+        let mut dst_buf = buffer_pool::get(buffer_size);
+        let mut copied = 0;
         while limit > 0 {
-            let limited_value = (if limit > 2000 { 2000 } else { limit }) as usize;
+            let limited_value = limit.min(throttle.chunk(dst_buf.len()));
             let size = dst
                 .read(&mut dst_buf[..limited_value])
                 .await
                 .or(Err(HttpError::LimitedTrancieverRead))?;
             if size == 0 {
-                return Ok(());
+                return Ok(copied);
             };
-            src.write_all(&mut dst_buf[..size])
+            throttle.consume(size).await;
+            src.write_all(&dst_buf[..size])
                 .await
                 .or(Err(HttpError::LimitedTrancieverWrite))?;
             limit -= size;
+            copied += size;
         }
-        Ok(())
+        Ok(copied)
     }
 
-    async fn chunked_transceiver<R, W>(src: &mut W, dst: &mut R) -> HttpResult<()>
+    /// Copy chunked body, return number of bytes copied
+    async fn chunked_transceiver<R, W>(
+        src: &mut W,
+        dst: &mut R,
+        buffer_size: usize,
+        throttle: &Throttle,
+    ) -> HttpResult<usize>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
+        let mut copied = 0;
         loop {
             //read_chunk_size
             let length_str = Self::read_line(dst).await?;
@@ -96,32 +323,49 @@ impl Http {
                 src.write_all(b"0\r\n\r\n")
                     .await
                     .or(Err(HttpError::ChunkTranciever))?;
-                return Ok(());
+                return Ok(copied + 5);
             } else {
                 src.write_all(length_str.as_bytes()).await.unwrap();
                 src.write_all(b"\r\n")
                     .await
                     .or(Err(HttpError::ChunkTranciever))?;
-                Self::limited_transceiver(src, dst, length + 2)
+                copied += length_str.len() + 2;
+                copied += Self::limited_transceiver(src, dst, length + 2, buffer_size, throttle)
                     .await
                     .or(Err(HttpError::ChunkTranciever))?;
             }
         }
     }
 
+    /// Header of the next request on a connection, `None` when shutdown
+    /// started before it came
+    async fn next_header<R>(sock: &mut R) -> HttpResult<Option<String>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        tokio::select! {
+            biased;
+            _ = session::stopped() => Ok(None),
+            header = Self::read_header(sock) => header.map(Some),
+        }
+    }
+
     async fn read_header<R>(sock: &mut R) -> HttpResult<String>
     where
         R: AsyncRead + Unpin,
     {
         let mut header = Vec::with_capacity(INITIAL_HEADER_CAPACITY);
         while !(header.ends_with(b"\r\n\r\n")) {
-            let byte = sock.read_u8().await.or(Err(HttpError::HeaderIncomplete))?;
+            let byte = sock.read_u8().await.map_err(|e| match e.kind() {
+                ErrorKind::TimedOut => HttpError::Timeout(Timeout::Idle),
+                _ => HttpError::HeaderIncomplete,
+            })?;
             header.push(byte);
             if header.len() > MAX_HEADER_HEADER_CAPACITY {
                 return Err(HttpError::HeaderToBig);
             }
         }
-        Ok(String::from_utf8(header).or(Err(HttpError::HeaderNotUtf8))?)
+        String::from_utf8(header).or(Err(HttpError::HeaderNotUtf8))
     }
     async fn read_line<R>(sock: &mut R) -> HttpResult<String>
     where
@@ -137,10 +381,15 @@ impl Http {
             }
         }
         result.resize(result.len() - 2, 0);
-        Ok(String::from_utf8(result).or(Err(HttpError::LineNotUtf8))?)
+        String::from_utf8(result).or(Err(HttpError::LineNotUtf8))
     }
 
-    async fn return_error_page<W, S>(src: &mut W, mut response: Response, body: S) -> HttpResult<()>
+    /// Send error page, return number of bytes sent
+    async fn return_error_page<W, S>(
+        src: &mut W,
+        mut response: Response,
+        body: S,
+    ) -> HttpResult<usize>
     where
         W: AsyncWrite + Unpin,
         S: AsRef<str>,
@@ -150,23 +399,190 @@ impl Http {
             .headers
             .insert_header("Content-Length", bytes.len().to_string());
         response.headers.insert_header("Content-Type", "text/html");
-        src.write_all(response.to_string().as_bytes())
+        let header = response.to_string();
+        src.write_all(header.as_bytes())
             .await
             .or(Err(HttpError::Internal))?;
         src.write_all(bytes).await.or(Err(HttpError::Internal))?;
-        Ok(())
+        Ok(header.len() + bytes.len())
     }
 
-    async fn http_parser(name: String, sock: TcpStream) -> HttpResult<()> {
+    /// Reply 403 to `request` of `received` header bytes, log it and
+    /// fail with `error`
+    async fn forbid<W, T>(
+        dst: &mut W,
+        request: &request::Request,
+        record: logger::Event,
+        received: usize,
+        started: Instant,
+        error: HttpError,
+    ) -> HttpResult<T>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let version = request.http_version.as_str();
+        let response = Response::new(version, 403, "forbidden", Headers::new());
+        let sent = Self::return_error_page(dst, response, ERROR_403).await?;
+        record
+            .status(403)
+            .bytes(received as u64, sent as u64)
+            .duration(started.elapsed())
+            .error(error.kind())
+            .log();
+        Err(error)
+    }
+
+    /// Reply 407 to `request` of `received` header bytes asking for
+    /// credentials of `challenges` and log it, or 503 if no scheme could
+    /// make one. Returns whether the client may send its next request on
+    /// the connection.
+    async fn challenge<W>(
+        dst: &mut W,
+        request: &request::Request,
+        record: logger::Event,
+        received: usize,
+        started: Instant,
+        error: HttpError,
+        challenges: Vec<String>,
+    ) -> HttpResult<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        // unread request body would be taken for the next request
+        let keep = request.headers.is_keep_alive() && !request.has_body();
+        let (status, reason, page) = match challenges.is_empty() {
+            true => (503, "service unavailable", ERROR_503),
+            false => (407, "proxy authentication required", ERROR_407),
+        };
+        let mut headers = Headers::new();
+        for challenge in challenges {
+            headers.insert_header("Proxy-Authenticate", challenge);
+        }
+        if !keep {
+            headers.insert_header("Connection", "close");
+        }
+        let version = request.http_version.as_str();
+        let response = Response::new(version, status, reason, headers);
+        let sent = Self::return_error_page(dst, response, page).await?;
+        record
+            .status(status)
+            .bytes(received as u64, sent as u64)
+            .duration(started.elapsed())
+            .error(error.kind())
+            .log();
+        Ok(keep)
+    }
+
+    /// User of `request` if its credentials are valid. Basic credentials
+    /// accepted before on the connection are kept in `authorized` and not
+    /// checked again until the users file changes, Digest ones are as each
+    /// carries a new nonce count.
+    async fn authenticate(
+        policy: &Policy,
+        users: &Users,
+        request: &request::Request,
+        authorized: &mut Option<(String, String, Snapshot)>,
+    ) -> HttpResult<String> {
+        let value = request
+            .headers
+            .combined_value("Proxy-Authorization")
+            .ok_or(HttpError::AuthRequired)?;
+        if let Some((accepted, user, snapshot)) = authorized {
+            if *accepted == value && users.is_current(snapshot) {
+                return Ok(user.clone());
+            }
+        }
+        if let Some((scheme, credentials)) = value.trim_start().split_once(' ') {
+            if let (true, Some(digest)) = (scheme.eq_ignore_ascii_case("digest"), &policy.digest) {
+                let method = request.method.as_str();
+                return digest
+                    .verify(users, method, &request.url, credentials)
+                    .map_err(|e| match e {
+                        DigestError::Invalid => HttpError::AuthFailed,
+                        DigestError::Stale => HttpError::NonceStale,
+                        DigestError::Replayed => HttpError::NonceReplayed,
+                    });
+            }
+        }
+        if !policy.basic {
+            return Err(HttpError::AuthFailed);
+        }
+        let (user, password) = auth::basic_credentials(&value).ok_or(HttpError::AuthFailed)?;
+        let snapshot = users.snapshot();
+        if !users.verify(&user, &password).await {
+            return Err(HttpError::AuthFailed);
+        }
+        *authorized = Some((value, user.clone(), snapshot));
+        Ok(user)
+    }
+
+    /// Connect to `authority` of a CONNECT request, failing with a
+    /// handshake timeout if that takes longer than `deadline`
+    async fn connect_target(
+        policy: &Policy,
+        authority: &str,
+        deadline: Duration,
+    ) -> HttpResult<Result<TcpStream, ConnectError>> {
+        let connect = async {
+            match policy.allows_connect(authority) {
+                true => acl::connect(&policy.acl, authority).await,
+                false => Err(ConnectError::Denied),
+            }
+        };
+        tokio::time::timeout(deadline, connect)
+            .await
+            .or(Err(HttpError::Timeout(Timeout::Handshake)))
+    }
+
+    /// Access record of a plain HTTP request
+    fn request_record(
+        name: &str,
+        src_ip: &PeerAddr,
+        request: &request::Request,
+        user: Option<&str>,
+    ) -> logger::Event {
+        let record = logger::access("request")
+            .engine("http", name)
+            .client(src_ip)
+            .target(&request.url)
+            .method(request.method.as_str())
+            .version(format!("HTTP/{}", request.http_version))
+            .referer(request.headers.combined_value("Referer"))
+            .user_agent(request.headers.combined_value("User-Agent"));
+        match user {
+            Some(user) => record.user(user),
+            None => record,
+        }
+    }
+
+    async fn http_parser(
+        name: &str,
+        sock: Stream,
+        relay: RelayOptions,
+        limiter: &Limiter,
+        policy: &Policy,
+    ) -> HttpResult<Option<Tunnel>> {
         let src_ip = sock.peer_addr().unwrap();
         //read header
         sock.set_nodelay(true).or(Err(HttpError::Internal))?;
         let mut connection_pool = connection_pool::ConnectionPool::new();
         let mut timed_out_stream = TimeoutStream::new(sock);
-        timed_out_stream.set_read_timeout(Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)));
+        timed_out_stream.set_read_timeout(Some(relay.idle_timeout));
         let mut timed_out_stream = Box::pin(timed_out_stream);
+        let handshake = Self::next_header(&mut timed_out_stream);
+        let mut first_header = tokio::time::timeout(relay.handshake_timeout, handshake)
+            .await
+            .or(Err(HttpError::Timeout(Timeout::Handshake)))??;
+        let mut authorized = None;
         'main: loop {
-            let header = Self::read_header(&mut timed_out_stream).await?;
+            let header = match first_header.take() {
+                Some(header) => header,
+                None => match Self::next_header(&mut timed_out_stream).await? {
+                    Some(header) => header,
+                    None => break 'main,
+                },
+            };
+            let started = Instant::now();
             let request = match parser::request(header.as_str()) {
                 Ok((_rest, request)) => request,
                 Err(_) => {
@@ -175,26 +591,94 @@ impl Http {
                     return Err(HttpError::HeaderParseError);
                 }
             };
+            let user = match &policy.users {
+                Some(users) => match Self::authenticate(policy, users, &request, &mut authorized)
+                    .await
+                {
+                    Ok(user) => Some(user),
+                    Err(error) => {
+                        let record = Self::request_record(name, &src_ip, &request, None);
+                        let challenges = policy.challenges(matches!(error, HttpError::NonceStale));
+                        let keep = Self::challenge(
+                            &mut timed_out_stream,
+                            &request,
+                            record,
+                            header.len(),
+                            started,
+                            error,
+                            challenges,
+                        )
+                        .await?;
+                        match keep {
+                            true => continue 'main,
+                            false => break 'main,
+                        }
+                    }
+                },
+                None => None,
+            };
+            let throttles = limiter.session(src_ip.ip(), user.as_deref());
             //analyze request
             if request.method == "CONNECT" {
                 request.headers.keep_alive_value();
-                let dst_sock = TcpStream::connect(&request.url)
-                    .await
-                    .or(Err(HttpError::TargetUnreachable(request.url.clone())))?;
+                if !policy.allows_host(authority_host(&request.url)) {
+                    let record = Self::request_record(name, &src_ip, &request, user.as_deref());
+                    let error = HttpError::DomainDenied(request.url.clone());
+                    return Self::forbid(
+                        &mut timed_out_stream,
+                        &request,
+                        record,
+                        header.len(),
+                        started,
+                        error,
+                    )
+                    .await;
+                }
+                let connected =
+                    Self::connect_target(policy, &request.url, relay.handshake_timeout).await?;
+                let dst_sock = match connected {
+                    Ok(sock) => sock,
+                    Err(ConnectError::Denied) => {
+                        let record = Self::request_record(name, &src_ip, &request, user.as_deref());
+                        let error = HttpError::TargetDenied(request.url.clone());
+                        return Self::forbid(
+                            &mut timed_out_stream,
+                            &request,
+                            record,
+                            header.len(),
+                            started,
+                            error,
+                        )
+                        .await;
+                    }
+                    Err(ConnectError::Unreachable) => {
+                        return Err(HttpError::TargetUnreachable(request.url.clone()));
+                    }
+                };
                 let dst_ip = dst_sock.peer_addr().unwrap();
                 let reply = format!("HTTP/{} 200 OK\r\n\r\n", request.http_version);
                 timed_out_stream
                     .write_all(reply.as_bytes())
                     .await
                     .or(Err(HttpError::Internal))?;
-                logger::log(format!("http.{} CONECT {:?} -> {:?}", name, src_ip, dst_ip));
-                let mut dst_timed_out = TimeoutStream::new(dst_sock);
-                dst_timed_out.set_read_timeout(Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)));
-                let mut dst_timed_out = Box::pin(dst_timed_out);
-                util::transceiver(&mut timed_out_stream, &mut dst_timed_out)
-                    .await
-                    .or(Err(HttpError::LimitedTranciever))?;
-                break;
+                let event = logger::access("session")
+                    .engine("http", name)
+                    .client(&src_ip)
+                    .target(&request.url)
+                    .method("CONNECT")
+                    .version(format!("HTTP/{}", request.http_version))
+                    .peer(dst_ip)
+                    .user_agent(request.headers.combined_value("User-Agent"))
+                    .status(200);
+                return Ok(Some(Tunnel {
+                    client: timed_out_stream,
+                    target: dst_sock,
+                    event: match &user {
+                        Some(user) => event.user(user),
+                        None => event,
+                    },
+                    throttles,
+                }));
             } else { // other request methods
                 // parse request
                 let (_rest, url) =
@@ -204,41 +688,96 @@ impl Http {
                 }
                 // connect to target
                 let to_resolve = format!("{}:{}", url.host, url.port);
-                let mut dst = match connection_pool.connect_or_reuse(&to_resolve).await {
+                if !policy.allows_host(&url.host) {
+                    let record = Self::request_record(name, &src_ip, &request, user.as_deref());
+                    let error = HttpError::DomainDenied(to_resolve);
+                    return Self::forbid(
+                        &mut timed_out_stream,
+                        &request,
+                        record,
+                        header.len(),
+                        started,
+                        error,
+                    )
+                    .await;
+                }
+                let connected = connection_pool
+                    .connect_or_reuse(&to_resolve, &policy.acl)
+                    .await;
+                let mut dst = match connected {
                     Ok(sock) => sock,
-                    Err(_) => {
+                    Err(ConnectError::Denied) => {
+                        let record = Self::request_record(name, &src_ip, &request, user.as_deref());
+                        let error = HttpError::TargetDenied(to_resolve);
+                        return Self::forbid(
+                            &mut timed_out_stream,
+                            &request,
+                            record,
+                            header.len(),
+                            started,
+                            error,
+                        )
+                        .await;
+                    }
+                    Err(ConnectError::Unreachable) => {
                         let response = Response::new(
-                            request.http_version,
+                            request.http_version.as_str(),
                             502,
                             "connection failed",
                             Headers::new(),
                         );
-                        Self::return_error_page(&mut timed_out_stream, response, ERROR_502).await?;
+                        let sent =
+                            Self::return_error_page(&mut timed_out_stream, response, ERROR_502)
+                                .await?;
+                        Self::request_record(name, &src_ip, &request, user.as_deref())
+                            .status(502)
+                            .bytes(header.len() as u64, sent as u64)
+                            .duration(started.elapsed())
+                            .error("target_unreachable")
+                            .log();
                         return Err(HttpError::TargetUnreachable(to_resolve));
                     }
                 };
                 //modify request
                 let mut new_request = request.clone();
                 new_request.url = url.path;
+                // credentials are for this proxy only
+                new_request.headers.remove_header("Proxy-Authorization");
                 dst.write_all(new_request.to_string().as_bytes())
                     .await
                     .or(Err(HttpError::Internal))?;
+                let mut received = header.len();
                 if request.has_body() {
                     // check request format (content-length or chunked)
                     if let Some(length) = request.headers.content_length() {
-                        Self::limited_transceiver(&mut *dst, &mut timed_out_stream, length).await?;
+                        received += Self::limited_transceiver(
+                            &mut *dst,
+                            &mut timed_out_stream,
+                            length,
+                            relay.buffer_size,
+                            &throttles.upload,
+                        )
+                        .await?;
                     } else if request.headers.is_chuncked() {
-                        Self::chunked_transceiver(&mut *dst, &mut timed_out_stream).await?;
+                        received += Self::chunked_transceiver(
+                            &mut *dst,
+                            &mut timed_out_stream,
+                            relay.buffer_size,
+                            &throttles.upload,
+                        )
+                        .await?;
                     }
                 }
                 //process response
                 let response_header = Self::read_header(&mut *dst).await?;
                 let (_input, response) = parser::response(response_header.as_str())
                     .or(Err(HttpError::ResponceHeaderParseError))?;
+                let response_header = response.to_string();
                 timed_out_stream
-                    .write_all(response.to_string().as_bytes())
+                    .write_all(response_header.as_bytes())
                     .await
                     .or(Err(HttpError::Internal))?;
+                let mut sent = response_header.len();
                 //update timeout values
                 if let Some(timeout) = response.headers.keep_alive_value() {
                     timed_out_stream
@@ -249,26 +788,100 @@ impl Http {
                 } else {
                     timed_out_stream
                         .as_mut()
-                        .set_read_timeout_pinned(Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)))
+                        .set_read_timeout_pinned(Some(relay.idle_timeout))
                 }
-                logger::log(format!(
-                    "http.{} {} {:?} -> {:?} {}",
-                    name, request.method, src_ip, request.url, response.status
-                ));
                 if response.has_body(&request) {
                     //check response format (contet-length or chunked)
                     if let Some(length) = response.headers.content_length() {
-                        Self::limited_transceiver(&mut timed_out_stream, &mut *dst, length + 2)
-                            .await?;
+                        sent += Self::limited_transceiver(
+                            &mut timed_out_stream,
+                            &mut *dst,
+                            length + 2,
+                            relay.buffer_size,
+                            &throttles.download,
+                        )
+                        .await?;
                     } else if response.headers.is_chuncked() {
-                        Self::chunked_transceiver(&mut timed_out_stream, &mut *dst).await?;
+                        sent += Self::chunked_transceiver(
+                            &mut timed_out_stream,
+                            &mut *dst,
+                            relay.buffer_size,
+                            &throttles.download,
+                        )
+                        .await?;
                     }
                 }
+                Self::request_record(name, &src_ip, &request, user.as_deref())
+                    .status(response.status)
+                    .peer(dst.peer_addr().map_or(to_resolve, |a| a.to_string()))
+                    .content_type(response.headers.combined_value("Content-Type"))
+                    .bytes(received as u64, sent as u64)
+                    .duration(started.elapsed())
+                    .log();
                 if !(request.headers.is_keep_alive() && response.headers.is_keep_alive()) {
                     break 'main;
                 }
             }
         }
-        Ok(())
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn policy() -> Policy {
+        Policy {
+            acl: Arc::new([]),
+            connect_ports: None,
+            domains: DomainFilter::new(None, None),
+            users: None,
+            realm: String::new(),
+            basic: true,
+            digest: None,
+        }
+    }
+    /// Connecting to a listener with a full backlog hangs on Linux, its
+    /// SYNs are dropped
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn connect_deadline() {
+        use socket2::{Domain, Socket, Type};
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(
+                &"127.0.0.1:0"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        listener.listen(0).unwrap();
+        let authority = listener
+            .local_addr()
+            .unwrap()
+            .as_socket()
+            .unwrap()
+            .to_string();
+        let mut queued = Vec::new();
+        let wait = Duration::from_millis(200);
+        while let Ok(Ok(sock)) = tokio::time::timeout(wait, TcpStream::connect(&authority)).await {
+            queued.push(sock);
+        }
+        let connected = Http::connect_target(&policy(), &authority, wait).await;
+        assert!(matches!(
+            connected,
+            Err(HttpError::Timeout(Timeout::Handshake))
+        ));
+    }
+    #[tokio::test]
+    async fn connect_denied() {
+        let denied = Policy {
+            connect_ports: Some("443".parse().unwrap()),
+            ..policy()
+        };
+        let wait = Duration::from_secs(1);
+        let connected = Http::connect_target(&denied, "127.0.0.1:80", wait).await;
+        assert!(matches!(connected, Ok(Err(ConnectError::Denied))));
     }
 }
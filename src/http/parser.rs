@@ -33,7 +33,7 @@ pub fn headers(input: &str) -> IResult<&str, Headers> {
     let (input, headers_raw) = many0(header_line)(input)?;
     let mut headers = Headers::new();
     for (k, v) in headers_raw {
-        headers.insert_header(k.to_string(), v);
+        headers.insert_header(k, v);
     }
     Ok((input, headers))
 }
@@ -98,15 +98,7 @@ pub fn response(input: &str) -> IResult<&str, Response> {
     let status: u16 = status.parse().unwrap();
     let (input, headers) = headers(input)?;
     let (input, _) = tag("\r\n")(input)?;
-    Ok((
-        input,
-        Response::new(
-            http_version.to_string(),
-            status,
-            phrase.to_string(),
-            headers,
-        ),
-    ))
+    Ok((input, Response::new(http_version, status, phrase, headers)))
 }
 
 fn domain(input: &str) -> IResult<&str, &str> {
@@ -19,21 +19,21 @@ impl Request {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{} {} HTTP/{}\r\n{}\r\n",
-            self.method,
-            self.url,
-            self.http_version,
-            self.headers.to_string()
-        )
-    }
-
     pub fn has_body(&self) -> bool {
         self.method == "POST" || self.method == "PUT"
     }
 }
 
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} HTTP/{}\r\n{}\r\n",
+            self.method, self.url, self.http_version, self.headers
+        )
+    }
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "{} {} HTTP/{}", self.method, self.url, self.http_version)?;
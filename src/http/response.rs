@@ -28,16 +28,6 @@ impl Response {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "HTTP/{} {} {}\r\n{}\r\n",
-            self.http_version,
-            self.status.to_string(),
-            self.status_phrase,
-            self.headers.to_string()
-        )
-    }
-
     pub fn has_body(&self, request: &Request) -> bool {
         !(request.method == "HEAD"
             || { self.status >= 100 } && (self.status < 200)
@@ -46,6 +36,16 @@ impl Response {
     }
 }
 
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "HTTP/{} {} {}\r\n{}\r\n",
+            self.http_version, self.status, self.status_phrase, self.headers
+        )
+    }
+}
+
 impl fmt::Debug for Response {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
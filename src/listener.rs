@@ -0,0 +1,475 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::config_loader::CommonConfig;
+use crate::logger;
+use crate::session;
+use serde::{Deserialize, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, Result};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Pauses after a failed accept
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Endpoint an engine listens on.
+///
+/// Parsed from strings:
+/// * `"3128"` or `"*:3128"` - all interfaces, dual-stack (IPv4 and IPv6)
+/// * `"127.0.0.1:3128"` - the given IPv4 address only
+/// * `"[::1]:3128"`, `"[::]:3128"` - the given IPv6 address only (IPv6-only socket)
+/// * `"unix:/run/proxy.sock"` - unix domain socket
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    Any(u16),
+    Ip(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Whether both endpoints can not be bound at the same time
+    pub fn overlaps(&self, other: &ListenAddr) -> bool {
+        use ListenAddr::*;
+        match (self, other) {
+            (Any(a), Any(b)) => a == b,
+            (Any(p), Ip(addr)) | (Ip(addr), Any(p)) => *p == addr.port(),
+            (Ip(a), Ip(b)) => {
+                a.port() == b.port()
+                    && a.is_ipv4() == b.is_ipv4()
+                    && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+            }
+            (Unix(a), Unix(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> String {
+        addr.to_string()
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("empty unix socket path".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let port_only = s.strip_prefix("*:").unwrap_or(s);
+        if let Ok(port) = port_only.parse::<u16>() {
+            return Ok(ListenAddr::Any(port));
+        }
+        s.parse::<SocketAddr>()
+            .map(ListenAddr::Ip)
+            .map_err(|_| format!("invalid listen address \"{}\"", s))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Any(port) => write!(f, "*:{}", port),
+            ListenAddr::Ip(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Accept both `listen = "addr"` and `listen = ["addr", ...]`
+pub fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<ListenAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ListenAddr),
+        Many(Vec<ListenAddr>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+/// Address of the connected client
+#[derive(Clone)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Unix(Option<PathBuf>),
+}
+
+impl PeerAddr {
+    /// Client IP address, IPv4-mapped IPv6 addresses as IPv4
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Ip(addr) => Some(addr.ip().to_canonical()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+impl fmt::Debug for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Client connection accepted by one of the engine listeners
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<PeerAddr> {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().map(PeerAddr::Ip),
+            #[cfg(unix)]
+            Stream::Unix(s) => Ok(PeerAddr::Unix(
+                s.peer_addr()?.as_pathname().map(|p| p.to_path_buf()),
+            )),
+        }
+    }
+
+    /// Write as much of `buf` as the socket takes without waiting. Unlike
+    /// `try_write` of tokio it works before the runtime polled the socket.
+    pub fn send_now(&self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(s) => SockRef::from(s).send(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => SockRef::from(s).send(buf),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// socket file is removed when listener is dropped
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn accept(&self) -> Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (s, addr) = l.accept().await?;
+                Ok((Stream::Tcp(s), PeerAddr::Ip(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l, _) => {
+                let (s, addr) = l.accept().await?;
+                let addr = PeerAddr::Unix(addr.as_pathname().map(|p| p.to_path_buf()));
+                Ok((Stream::Unix(s), addr))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+fn bind_tcp(sock_addr: SocketAddr, only_v6: bool) -> Result<Listener> {
+    let std_listener = Socket::new(
+        Domain::for_address(sock_addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if sock_addr.is_ipv6() {
+        std_listener.set_only_v6(only_v6)?;
+    }
+    std_listener.set_tcp_nodelay(true)?;
+    std_listener.set_reuse_address(true)?;
+    std_listener.set_nonblocking(true)?;
+    std_listener.bind(&sock_addr.into())?;
+    std_listener.listen(1024)?;
+    Ok(Listener::Tcp(TcpListener::from_std(std_listener.into())?))
+}
+
+/// Remove socket file left by a previous run.
+/// Fails if the file is not a socket or somebody still listens on it.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use", path.display()),
+        ));
+    }
+    std::fs::remove_file(path)
+}
+
+/// Resolve user or group given by name or numeric id
+#[cfg(unix)]
+fn resolve_id(name: &str, group: bool) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid name"))?;
+    // SAFETY: getpwnam/getgrnam are called with a valid C string and
+    // only the id is read from the returned record before any other call.
+    let id = unsafe {
+        if group {
+            let gr = libc::getgrnam(c_name.as_ptr());
+            (!gr.is_null()).then(|| (*gr).gr_gid)
+        } else {
+            let pw = libc::getpwnam(c_name.as_ptr());
+            (!pw.is_null()).then(|| (*pw).pw_uid)
+        }
+    };
+    id.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "unknown {} \"{}\"",
+                if group { "group" } else { "user" },
+                name
+            ),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, config: &CommonConfig) -> Result<Listener> {
+    use std::os::unix::fs::PermissionsExt;
+    remove_stale_socket(path)?;
+    let listener = Listener::Unix(UnixListener::bind(path)?, path.to_path_buf());
+    if let Some(mode) = config.unix_mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if config.unix_owner.is_some() || config.unix_group.is_some() {
+        let uid = config
+            .unix_owner
+            .as_deref()
+            .map(|u| resolve_id(u, false))
+            .transpose()?;
+        let gid = config
+            .unix_group
+            .as_deref()
+            .map(|g| resolve_id(g, true))
+            .transpose()?;
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &std::path::Path, _config: &CommonConfig) -> Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+pub fn bind(addr: &ListenAddr, config: &CommonConfig) -> Result<Listener> {
+    match addr {
+        ListenAddr::Any(port) => bind_tcp(
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), *port),
+            false,
+        ),
+        ListenAddr::Ip(addr) => bind_tcp(*addr, addr.is_ipv6()),
+        ListenAddr::Unix(path) => bind_unix(path, config),
+    }
+}
+
+/// Bind all endpoints of an engine, error names the endpoint that failed
+pub fn bind_all(config: &CommonConfig) -> Result<Vec<Listener>> {
+    config
+        .endpoints()
+        .iter()
+        .map(|addr| {
+            bind(addr, config)
+                .map_err(|e| io::Error::new(e.kind(), format!("can not listen on {}: {}", addr, e)))
+        })
+        .collect()
+}
+
+/// Accept connections on all `listeners` of `engine` (type and name) and
+/// serve each one in a separate session task created by `handler`.
+/// Clients `handler` refuses at once (`None`) get no task.
+///
+/// Failed accepts, like running out of file descriptors, are logged and
+/// retried after a growing pause.
+pub async fn accept_all<F, S>(engine: (&'static str, &str), listeners: Vec<Listener>, handler: F)
+where
+    F: Fn(Stream, PeerAddr) -> Option<S>,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let handler = &handler;
+    futures::future::join_all(listeners.into_iter().map(|listener| async move {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        loop {
+            match listener.accept().await {
+                Ok((sock, addr)) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    if let Some(session) = handler(sock, addr) {
+                        session::spawn(session);
+                    }
+                }
+                Err(e) => {
+                    logger::warn(format!(
+                        "accept failed, retrying in {}ms: {}",
+                        backoff.as_millis(),
+                        e
+                    ))
+                    .engine(engine.0, engine.1)
+                    .log();
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+            }
+        }
+    }))
+    .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn parse_listen_addr() {
+        assert_eq!("3128".parse(), Ok(ListenAddr::Any(3128)));
+        assert_eq!("*:3128".parse(), Ok(ListenAddr::Any(3128)));
+        assert_eq!(
+            "127.0.0.1:1080".parse(),
+            Ok(ListenAddr::Ip("127.0.0.1:1080".parse().unwrap()))
+        );
+        assert_eq!(
+            "[::1]:1080".parse(),
+            Ok(ListenAddr::Ip("[::1]:1080".parse().unwrap()))
+        );
+        assert_eq!(
+            "unix:/run/proxy/http.sock".parse(),
+            Ok(ListenAddr::Unix(PathBuf::from("/run/proxy/http.sock")))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost:80".parse::<ListenAddr>().is_err());
+        assert!("127.0.0.1".parse::<ListenAddr>().is_err());
+    }
+    // SO_REUSEADDR lets a second socket bind a taken port on Windows
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_error_names_endpoint() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let config: CommonConfig = toml::from_str(&format!("listen = \"{}\"", addr)).unwrap();
+        let error = bind_all(&config).err().unwrap();
+        assert!(error
+            .to_string()
+            .starts_with(&format!("can not listen on {}: ", addr)));
+    }
+    #[test]
+    fn listen_addr_overlaps() {
+        let p = |s: &str| s.parse::<ListenAddr>().unwrap();
+        assert!(p("3128").overlaps(&p("127.0.0.1:3128")));
+        assert!(p("[::1]:3128").overlaps(&p("*:3128")));
+        assert!(p("0.0.0.0:3128").overlaps(&p("127.0.0.1:3128")));
+        assert!(!p("127.0.0.1:3128").overlaps(&p("127.0.0.2:3128")));
+        assert!(!p("[::]:3128").overlaps(&p("0.0.0.0:3128")));
+        assert!(!p("3128").overlaps(&p("3129")));
+        assert!(p("unix:/a.sock").overlaps(&p("unix:/a.sock")));
+        assert!(!p("unix:/a.sock").overlaps(&p("3128")));
+    }
+}
@@ -1,7 +1,544 @@
-use chrono::offset::Local;
+use crate::access_log;
+use crate::util::Transfer;
+use chrono::{offset::Local, DateTime};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::{self, Display, Write as _};
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use std::time::Duration;
 
-pub fn log<S: AsRef<str>>(s: S) {
-    let dt = Local::now();
-    let sdt = dt.format("%Y-%m-%d %H:%M:%S:%.3f");
-    println!("{}: {}", sdt, s.as_ref());
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// human readable line
+    Text,
+    /// one JSON object per line
+    Json,
+    /// Squid native `access.log` format, other events as `text`
+    Squid,
+    /// Apache combined log format, other events as `text`
+    Combined,
+}
+
+/// `[log]` section of configuration
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LogConfig {
+    /// minimal level of logged events
+    pub level: Level,
+    pub format: Format,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: Level::Info,
+            format: Format::Text,
+        }
+    }
+}
+
+static CONFIG: RwLock<LogConfig> = RwLock::new(LogConfig {
+    level: Level::Info,
+    format: Format::Text,
+});
+
+pub fn configure(config: &LogConfig) {
+    *CONFIG.write().unwrap() = config.clone();
+}
+
+/// Log event with typed fields
+#[must_use = "event is logged by `log()`"]
+#[derive(Default)]
+pub struct Event {
+    time: DateTime<Local>,
+    level: Level,
+    /// access record, written to access log of the engine
+    access: bool,
+    message: String,
+    engine_type: Option<&'static str>,
+    engine: Option<String>,
+    client: Option<String>,
+    target: Option<String>,
+    method: Option<String>,
+    /// protocol of the request, `HTTP/1.1`, `SOCKS5`
+    version: Option<String>,
+    user: Option<String>,
+    /// address of the server the request was forwarded to
+    peer: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    content_type: Option<String>,
+    status: Option<u16>,
+    /// bytes received from client
+    bytes_received: Option<u64>,
+    /// bytes sent to client
+    bytes_sent: Option<u64>,
+    duration: Option<Duration>,
+    /// side that ended the session, `client` or `target`
+    closed_by: Option<String>,
+    error: Option<String>,
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(level: Level, message: S) -> Event {
+        Event {
+            time: Local::now(),
+            level,
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn engine(mut self, engine_type: &'static str, name: &str) -> Event {
+        self.engine_type = Some(engine_type);
+        self.engine = Some(name.to_string());
+        self
+    }
+
+    pub fn client<D: Display>(mut self, client: D) -> Event {
+        self.client = Some(client.to_string());
+        self
+    }
+
+    pub fn target<D: Display>(mut self, target: D) -> Event {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn method<S: Into<String>>(mut self, method: S) -> Event {
+        self.method = Some(method.into());
+        self
+    }
+
+    pub fn version<S: Into<String>>(mut self, version: S) -> Event {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn user<S: Into<String>>(mut self, user: S) -> Event {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn peer<D: Display>(mut self, peer: D) -> Event {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    pub fn referer(mut self, referer: Option<String>) -> Event {
+        self.referer = referer;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: Option<String>) -> Event {
+        self.user_agent = user_agent;
+        self
+    }
+
+    pub fn content_type(mut self, content_type: Option<String>) -> Event {
+        self.content_type = content_type;
+        self
+    }
+
+    pub fn status(mut self, status: u16) -> Event {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn bytes(mut self, received: u64, sent: u64) -> Event {
+        self.bytes_received = Some(received);
+        self.bytes_sent = Some(sent);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Event {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Byte counts, duration, closing side and timeout of relayed session
+    pub fn transfer(mut self, transfer: &Transfer) -> Event {
+        self.closed_by = Some(transfer.closed_by.to_string());
+        if let Some(timeout) = transfer.timeout {
+            self.error = Some(timeout.kind().to_string());
+        }
+        self.bytes(transfer.upload, transfer.download)
+            .duration(transfer.duration)
+    }
+
+    /// Kind of error, a short identifier like `target_unreachable`. Error
+    /// types give theirs by a `kind()` method, in snake_case and stable
+    /// across releases, as log processing matches on them.
+    pub fn error<D: Display>(mut self, kind: D) -> Event {
+        self.error = Some(kind.to_string());
+        self
+    }
+
+    fn fields(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("client", self.client.as_deref()),
+            ("target", self.target.as_deref()),
+            ("method", self.method.as_deref()),
+            ("version", self.version.as_deref()),
+            ("user", self.user.as_deref()),
+            ("peer", self.peer.as_deref()),
+            ("referer", self.referer.as_deref()),
+            ("user_agent", self.user_agent.as_deref()),
+            ("content_type", self.content_type.as_deref()),
+            ("closed_by", self.closed_by.as_deref()),
+            ("error", self.error.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k, v)))
+        .collect()
+    }
+
+    fn numeric_fields(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("status", self.status.map(u64::from)),
+            ("bytes_received", self.bytes_received),
+            ("bytes_sent", self.bytes_sent),
+            ("duration_ms", self.duration.map(|d| d.as_millis() as u64)),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k, v)))
+        .collect()
+    }
+
+    fn to_text(&self) -> String {
+        let mut result = format!(
+            "{} {:5}",
+            self.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.level.to_string().to_uppercase()
+        );
+        if let (Some(engine_type), Some(engine)) = (self.engine_type, &self.engine) {
+            write!(result, " {}.{}", engine_type, engine).unwrap();
+        }
+        write!(result, " {}", self.message).unwrap();
+        for (k, v) in self.fields() {
+            if v.is_empty() || v.contains(|c: char| c.is_whitespace() || c == '"') {
+                write!(result, " {}={:?}", k, v).unwrap();
+            } else {
+                write!(result, " {}={}", k, v).unwrap();
+            }
+        }
+        for (k, v) in self.numeric_fields() {
+            write!(result, " {}={}", k, v).unwrap();
+        }
+        result
+    }
+
+    fn to_json(&self) -> String {
+        let mut result = format!(
+            "{{\"ts\":\"{}\",\"level\":\"{}\"",
+            self.time.format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            self.level
+        );
+        if let (Some(engine_type), Some(engine)) = (self.engine_type, &self.engine) {
+            write!(
+                result,
+                ",\"engine_type\":{},\"engine\":{}",
+                json_string(engine_type),
+                json_string(engine)
+            )
+            .unwrap();
+        }
+        write!(result, ",\"msg\":{}", json_string(&self.message)).unwrap();
+        for (k, v) in self.fields() {
+            write!(result, ",\"{}\":{}", k, json_string(v)).unwrap();
+        }
+        for (k, v) in self.numeric_fields() {
+            write!(result, ",\"{}\":{}", k, v).unwrap();
+        }
+        result.push('}');
+        result
+    }
+
+    /// Client address without port
+    fn client_host(&self) -> String {
+        let client = self.client.as_deref().unwrap_or("-");
+        match client.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_canonical().to_string(),
+            Err(_) => client.to_string(),
+        }
+    }
+
+    /// HTTP status. Sessions of other protocols are reported as 200 once
+    /// their target is connected, however they end, or 503 if they
+    /// failed before.
+    fn reported_status(&self) -> u16 {
+        match (self.status, &self.peer, &self.error) {
+            (Some(status), _, _) => status,
+            (None, None, Some(_)) => 503,
+            _ => 200,
+        }
+    }
+
+    /// Squid native format:
+    /// `time elapsed client code/status bytes method url user hierarchy/peer type`
+    fn to_squid(&self) -> String {
+        let status = self.reported_status();
+        let code = match self.method.as_deref() {
+            _ if self.engine_type != Some("http") => "TCP_TUNNEL",
+            Some("CONNECT") if status == 200 => "TCP_TUNNEL",
+            _ => "TCP_MISS",
+        };
+        let hierarchy = match &self.peer {
+            Some(peer) => format!("HIER_DIRECT/{}", peer_host(peer)),
+            None => "HIER_NONE/-".to_string(),
+        };
+        format!(
+            "{}.{:03} {:6} {} {}/{:03} {} {} {} {} {} {}",
+            self.time.timestamp(),
+            self.time.timestamp_subsec_millis(),
+            self.duration.map_or(0, |d| d.as_millis()),
+            self.client_host(),
+            code,
+            status,
+            self.bytes_sent.unwrap_or(0),
+            self.method.as_deref().unwrap_or("-"),
+            self.target.as_deref().unwrap_or("-"),
+            self.user.as_deref().unwrap_or("-"),
+            hierarchy,
+            self.content_type.as_deref().unwrap_or("-"),
+        )
+    }
+
+    /// Apache combined format:
+    /// `client - user [time] "request" status bytes "referer" "user-agent"`
+    fn to_combined(&self) -> String {
+        let start = match self
+            .duration
+            .and_then(|d| chrono::Duration::from_std(d).ok())
+        {
+            Some(duration) => self.time - duration,
+            None => self.time,
+        };
+        let bytes = match self.bytes_sent {
+            Some(bytes) if bytes > 0 => bytes.to_string(),
+            _ => "-".to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            self.client_host(),
+            self.user.as_deref().unwrap_or("-"),
+            start.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method.as_deref().unwrap_or("-"),
+            self.target.as_deref().unwrap_or("-"),
+            self.version.as_deref().unwrap_or("-"),
+            self.reported_status(),
+            bytes,
+            escape_quoted(self.referer.as_deref().unwrap_or("-")),
+            escape_quoted(self.user_agent.as_deref().unwrap_or("-")),
+        )
+    }
+
+    fn format(&self, format: Format) -> String {
+        match format {
+            Format::Json => self.to_json(),
+            Format::Squid if self.access => self.to_squid(),
+            Format::Combined if self.access => self.to_combined(),
+            _ => self.to_text(),
+        }
+    }
+
+    /// Write access records to the engine's access log or stdout and
+    /// other events to stderr. Access records are written whatever
+    /// the configured level is.
+    pub fn log(mut self) {
+        self.time = Local::now();
+        let format = {
+            let config = CONFIG.read().unwrap();
+            if !self.access && self.level > config.level {
+                return;
+            }
+            config.format
+        };
+        if !self.access {
+            eprintln!("{}", self.format(format));
+            return;
+        }
+        let access_log = match (self.engine_type, &self.engine) {
+            (Some(engine_type), Some(engine)) => {
+                access_log::get(&format!("{}.{}", engine_type, engine))
+            }
+            _ => None,
+        };
+        match access_log {
+            Some(access_log) => {
+                access_log.write(&self.format(access_log.format().unwrap_or(format)))
+            }
+            None => println!("{}", self.format(format)),
+        }
+    }
+}
+
+/// Host part of `host:port` address
+fn peer_host(peer: &str) -> String {
+    match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_canonical().to_string(),
+        Err(_) => peer
+            .rsplit_once(':')
+            .map_or(peer, |(host, _port)| host)
+            .to_string(),
+    }
+}
+
+/// Escape `"` and `\` inside quoted field of combined format
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(result, "\\u{:04x}", c as u32).unwrap(),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+pub fn error<S: Into<String>>(message: S) -> Event {
+    Event::new(Level::Error, message)
+}
+
+pub fn warn<S: Into<String>>(message: S) -> Event {
+    Event::new(Level::Warn, message)
+}
+
+pub fn info<S: Into<String>>(message: S) -> Event {
+    Event::new(Level::Info, message)
+}
+
+/// Record of a served request or session
+pub fn access<S: Into<String>>(message: S) -> Event {
+    Event {
+        access: true,
+        ..Event::new(Level::Info, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn json_event() {
+        let json = info("request")
+            .engine("http", "a")
+            .client("127.0.0.1:1000")
+            .target("http://example.net/\"q\"")
+            .method("GET")
+            .status(200)
+            .to_json();
+        assert!(json.ends_with(
+            r#""level":"info","engine_type":"http","engine":"a","msg":"request","client":"127.0.0.1:1000","target":"http://example.net/\"q\"","method":"GET","status":200}"#
+        ), "{}", json);
+    }
+    #[test]
+    fn text_event() {
+        let text = warn("client error")
+            .engine("socks5", "b")
+            .error("target_unreachable")
+            .to_text();
+        assert!(
+            text.ends_with(" WARN  socks5.b client error error=target_unreachable"),
+            "{}",
+            text
+        );
+    }
+    fn http_record() -> Event {
+        use chrono::TimeZone;
+        let mut event = access("request")
+            .engine("http", "a")
+            .client("[::ffff:10.0.0.1]:5000")
+            .target("http://example.net/")
+            .method("GET")
+            .version("HTTP/1.1")
+            .peer("93.184.216.34:80")
+            .user_agent(Some("curl/8.0 \"x\"".to_string()))
+            .content_type(Some("text/html".to_string()))
+            .status(200)
+            .bytes(80, 1234)
+            .duration(Duration::from_millis(120));
+        event.time = Local.timestamp_opt(1700000000, 250_000_000).unwrap();
+        event
+    }
+    #[test]
+    fn squid_record() {
+        assert_eq!(
+            http_record().to_squid(),
+            "1700000000.250    120 10.0.0.1 TCP_MISS/200 1234 GET http://example.net/ - HIER_DIRECT/93.184.216.34 text/html"
+        );
+        let socks = access("connect")
+            .engine("socks5", "b")
+            .client("127.0.0.1:4000")
+            .target("example.net:443")
+            .method("CONNECT")
+            .to_squid();
+        assert!(
+            socks.ends_with(" 127.0.0.1 TCP_TUNNEL/200 0 CONNECT example.net:443 - HIER_NONE/- -"),
+            "{}",
+            socks
+        );
+        let tunnel = access("session")
+            .engine("tcppm", "c")
+            .client("127.0.0.1:4000")
+            .method("CONNECT")
+            .peer("93.184.216.34:443")
+            .bytes(10, 20)
+            .error("idle_timeout");
+        assert!(tunnel.to_squid().contains(" TCP_TUNNEL/200 20 "));
+        let failed = access("session")
+            .engine("tcppm", "c")
+            .error("target_unreachable");
+        assert!(failed.to_squid().contains(" TCP_TUNNEL/503 0 "));
+    }
+    #[test]
+    fn combined_record() {
+        let line = http_record().to_combined();
+        assert!(line.starts_with("10.0.0.1 - - ["), "{}", line);
+        assert!(
+            line.ends_with(r#"] "GET http://example.net/ HTTP/1.1" 200 1234 "-" "curl/8.0 \"x\"""#),
+            "{}",
+            line
+        );
+    }
+    #[test]
+    fn levels() {
+        assert!(Level::Error < Level::Info);
+        assert!(Level::Trace > Level::Debug);
+    }
 }
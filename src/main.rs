@@ -10,22 +10,93 @@ extern crate lru_cache;
 extern crate socket2;
 
 pub(crate) mod util;
+mod buffer_pool;
+mod acl;
+mod admission;
+mod access_log;
+mod listener;
 mod logger;
 mod tcppm;
 mod socks4;
 mod socks5;
 mod http;
+mod cli;
+mod config_defaults;
+mod config_files;
 mod config_loader;
 mod config_spawner;
+mod config_validator;
+mod domains;
+mod session;
+#[cfg(target_os = "linux")]
+mod splice;
+mod signals;
+mod throttle;
+mod users;
+mod watched_file;
+use cli::{Command, ConfigSource};
 use std::env;
+use std::process::exit;
+
+fn load_or_exit(source: &ConfigSource) -> config_loader::Config {
+    match source.load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1)
+        }
+    }
+}
+
+/// Validate configuration without starting engines, returns exit code
+fn check(source: &ConfigSource) -> i32 {
+    let result = source.load().and_then(|c| {
+        config_validator::check_targets(&c).map_err(|e| match source.path() {
+            Some(path) => e.in_file(&path),
+            None => e,
+        })
+    });
+    match result {
+        Ok(()) => {
+            println!(
+                "{}: configuration is valid",
+                source.path().as_deref().unwrap_or("proxy")
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let c = if env::args().len() > 1 {
-        config_loader::load_config(env::args().nth(1).unwrap())
-    } else {
-        println!("using default configuration");
-        config_loader::load_config_default()
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            exit(2)
+        }
     };
-    config_spawner::spawn(c).await;
-}
\ No newline at end of file
+    match command {
+        Command::Run(source) => {
+            if source == ConfigSource::Default {
+                println!("using default configuration");
+            }
+            let c = load_or_exit(&source);
+            config_spawner::spawn(c, source.path()).await;
+        }
+        Command::Check(source) => exit(check(&source)),
+        Command::PrintDefaultConfig => print!("{}", config_loader::DEFAULT_CONFIG),
+        Command::PrintEffectiveConfig(source) => {
+            let mut c = toml::Table::try_from(load_or_exit(&source)).unwrap();
+            // engine types without engines
+            c.retain(|_, v| v.as_table().is_none_or(|t| !t.is_empty()));
+            print!("{}", toml::to_string(&c).unwrap());
+        }
+        Command::Version => println!("proxy {}", env!("CARGO_PKG_VERSION")),
+        Command::Help => println!("{}", cli::USAGE),
+    }
+}
@@ -0,0 +1,78 @@
+//! Tracking of accepted client sessions, used to drain them on shutdown
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static FINISHED: Notify = Notify::const_new();
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOP: Notify = Notify::const_new();
+
+struct SessionGuard;
+
+impl SessionGuard {
+    fn new() -> SessionGuard {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        SessionGuard
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if ACTIVE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            FINISHED.notify_waiters();
+        }
+    }
+}
+
+/// Spawn task serving one client session
+pub fn spawn<F>(session: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let guard = SessionGuard::new();
+    tokio::spawn(async move {
+        let _guard = guard;
+        session.await
+    });
+}
+
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Tell open sessions that shutdown started, so they finish the request
+/// in flight and close idle connections
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+    STOP.notify_waiters();
+}
+
+/// Resolves once shutdown started
+pub async fn stopped() {
+    loop {
+        let stop = STOP.notified();
+        if STOPPING.load(Ordering::SeqCst) {
+            return;
+        }
+        stop.await;
+    }
+}
+
+/// Wait until all sessions are finished or `timeout` expires.
+/// Returns number of sessions still open.
+pub async fn drain(timeout: Duration) -> usize {
+    let wait_all = async {
+        loop {
+            let finished = FINISHED.notified();
+            if active() == 0 {
+                return;
+            }
+            finished.await;
+        }
+    };
+    tokio::time::timeout(timeout, wait_all).await.ok();
+    active()
+}
@@ -0,0 +1,63 @@
+//! Process signals the proxy reacts to
+
+pub enum Signal {
+    /// SIGHUP: reload configuration
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Reload,
+    /// SIGUSR1: reopen access log files
+    #[cfg_attr(not(unix), allow(dead_code))]
+    ReopenLogs,
+    /// SIGUSR2: log session and buffer pool statistics
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Stats,
+    /// SIGTERM or SIGINT (Ctrl-C): graceful shutdown
+    Shutdown,
+}
+
+#[cfg(unix)]
+pub struct Signals {
+    hup: tokio::signal::unix::Signal,
+    usr1: tokio::signal::unix::Signal,
+    usr2: tokio::signal::unix::Signal,
+    term: tokio::signal::unix::Signal,
+    int: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    pub fn new() -> Signals {
+        use tokio::signal::unix::{signal, SignalKind};
+        Signals {
+            hup: signal(SignalKind::hangup()).unwrap(),
+            usr1: signal(SignalKind::user_defined1()).unwrap(),
+            usr2: signal(SignalKind::user_defined2()).unwrap(),
+            term: signal(SignalKind::terminate()).unwrap(),
+            int: signal(SignalKind::interrupt()).unwrap(),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.hup.recv() => Signal::Reload,
+            _ = self.usr1.recv() => Signal::ReopenLogs,
+            _ = self.usr2.recv() => Signal::Stats,
+            _ = self.term.recv() => Signal::Shutdown,
+            _ = self.int.recv() => Signal::Shutdown,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    pub fn new() -> Signals {
+        Signals
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        tokio::signal::ctrl_c().await.unwrap();
+        Signal::Shutdown
+    }
+}
@@ -1,7 +1,12 @@
-use super::util;
+use super::util::{self, RelayOptions, Timeout};
+use crate::acl::{self, ConnectError, Rule};
+use crate::admission::{self, Admission, Permit};
 use crate::config_loader::Socks4Config;
+use crate::listener::{self, Listener, Stream};
 use crate::logger;
+use crate::throttle::Limiter;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
@@ -12,12 +17,40 @@ pub enum Socks4Error {
     Handshake,
     HeaderInvalid,
     TargetUnreachable,
+    TargetDenied,
     Transceiver,
+    Timeout(Timeout),
+}
+
+impl Socks4Error {
+    /// Kind of a failed SOCKS4 session
+    fn kind(&self) -> &'static str {
+        match self {
+            Socks4Error::Handshake => "handshake",
+            Socks4Error::HeaderInvalid => "header_invalid",
+            Socks4Error::TargetUnreachable => "target_unreachable",
+            Socks4Error::TargetDenied => "target_denied",
+            Socks4Error::Transceiver => "transceiver",
+            Socks4Error::Timeout(timeout) => timeout.kind(),
+        }
+    }
 }
 
 type Socks4Result<T> = Result<T, Socks4Error>;
 
 const MAX_ID_LENGTH: usize = 1000;
+const GOOD_REPLY: [u8; 8] = [
+    0x00u8, //VN
+    0x5a,   //Granted
+    0x00, 0x00, //DSTPORT,
+    0x00, 0x00, 0x00, 0x00,
+]; //DSTIP
+const BAD_REPLY: [u8; 8] = [
+    0x00u8, //VN
+    0x5b,   //Failed
+    0x00, 0x00, //DSTPORT,
+    0x00, 0x00, 0x00, 0x00,
+]; //DSTIP
 
 #[derive(Debug)]
 pub struct Request {
@@ -30,23 +63,48 @@ pub struct Request {
 pub struct Socks4 {
     name: String,
     config: Socks4Config,
+    limiter: Arc<Limiter>,
+    admission: Admission,
+    target_acl: Arc<[Rule]>,
 }
 
 impl Socks4 {
-    pub fn new(name: &String, config: &Socks4Config) -> Socks4 {
+    pub fn new(name: &str, config: &Socks4Config) -> Socks4 {
         Socks4 {
-            name: name.clone(),
+            name: name.to_string(),
             config: config.clone(),
+            limiter: Arc::new(Limiter::new(config.common.throttle.as_ref())),
+            admission: Admission::new(&config.common),
+            target_acl: config.common.target_rules(),
         }
     }
 
-    pub async fn serve(&self) {
-        let listener = util::bind_listener(self.config.port).await;
-        loop {
-            let (sock, _addr) = listener.accept().await.unwrap();
+    pub async fn serve(&self, listeners: Vec<Listener>) {
+        listener::accept_all(("socks4", &self.name), listeners, |sock, addr| {
             let name_clone = self.name.clone();
-            tokio::spawn(async move { Self::socks4_parser(name_clone, sock).await.ok() });
-        }
+            let relay = self.config.common.relay_options();
+            let permit = match self.admission.admit(addr.ip()) {
+                Ok(permit) => permit,
+                Err(refusal) => {
+                    admission::refuse(("socks4", &self.name), sock, &addr, refusal, &BAD_REPLY);
+                    return None;
+                }
+            };
+            let limiter = self.limiter.clone();
+            let target_acl = self.target_acl.clone();
+            Some(async move {
+                let session =
+                    Self::socks4_parser(&name_clone, sock, relay, &limiter, permit, &target_acl);
+                if let Err(e) = session.await {
+                    logger::warn("session failed")
+                        .engine("socks4", &name_clone)
+                        .client(addr)
+                        .error(e.kind())
+                        .log();
+                }
+            })
+        })
+        .await;
     }
 
     async fn read_request<R>(sock: &mut R) -> Socks4Result<Request>
@@ -71,43 +129,64 @@ impl Socks4 {
         })
     }
 
-    async fn socks4_parser(name: String, mut sock: TcpStream) -> Socks4Result<()> {
-        const GOOD_REPLY: [u8; 8] = [
-            0x00u8, //VN
-            0x5a,   //Granted
-            0x00, 0x00, //DSTPORT,
-            0x00, 0x00, 0x00, 0x00,
-        ]; //DSTIP
-        const BAD_REPLY: [u8; 8] = [
-            0x00u8, //VN
-            0x5b,   //Failed
-            0x00, 0x00, //DSTPORT,
-            0x00, 0x00, 0x00, 0x00,
-        ]; //DSTIP
-        sock.set_nodelay(true).ok();
-        let request = Self::read_request(&mut sock).await?;
+    /// Read request, connect to its target if `target_acl` allows and reply
+    async fn socks4_handshake(
+        sock: &mut Stream,
+        target_acl: &[Rule],
+    ) -> Socks4Result<(Request, TcpStream)> {
+        let request = Self::read_request(sock).await?;
         if request.cmd != 1 {
             sock.write_all(&BAD_REPLY).await.ok();
             return Err(Socks4Error::HeaderInvalid);
         }
-        let dst = TcpStream::connect(&request.dst).await;
-        if let Ok(mut dst) = dst {
-            sock.write_all(&GOOD_REPLY)
-                .await
-                .or(Err(Socks4Error::Handshake))?;
-            logger::log(format!(
-                "socs4.{} {:?} {} -> {:?}",
-                name,
-                sock.peer_addr().or(Err(Socks4Error::Handshake))?,
-                request.id,
-                dst.peer_addr().or(Err(Socks4Error::Handshake))?
-            ));
-            util::transceiver(&mut sock, &mut dst)
-                .await
-                .or(Err(Socks4Error::Transceiver))
-        } else {
-            sock.write_all(&BAD_REPLY).await.ok();
-            Err(Socks4Error::TargetUnreachable)
+        match acl::connect(target_acl, request.dst).await {
+            Ok(dst) => {
+                sock.write_all(&GOOD_REPLY)
+                    .await
+                    .or(Err(Socks4Error::Handshake))?;
+                Ok((request, dst))
+            }
+            Err(e) => {
+                sock.write_all(&BAD_REPLY).await.ok();
+                match e {
+                    ConnectError::Denied => Err(Socks4Error::TargetDenied),
+                    ConnectError::Unreachable => Err(Socks4Error::TargetUnreachable),
+                }
+            }
+        }
+    }
+
+    async fn socks4_parser(
+        name: &str,
+        mut sock: Stream,
+        relay: RelayOptions,
+        limiter: &Limiter,
+        _permit: Permit,
+        target_acl: &[Rule],
+    ) -> Socks4Result<()> {
+        sock.set_nodelay(true).ok();
+        let handshake = Self::socks4_handshake(&mut sock, target_acl);
+        let (request, mut dst) = tokio::time::timeout(relay.handshake_timeout, handshake)
+            .await
+            .or(Err(Socks4Error::Timeout(Timeout::Handshake)))??;
+        let client = sock.peer_addr().or(Err(Socks4Error::Handshake))?;
+        let throttles = limiter.session(client.ip(), None);
+        let mut event = logger::access("session")
+            .engine("socks4", name)
+            .client(client)
+            .target(request.dst)
+            .method("CONNECT")
+            .version("SOCKS4")
+            .peer(dst.peer_addr().or(Err(Socks4Error::Handshake))?);
+        if !request.id.is_empty() {
+            event = event.user(request.id);
+        }
+        let transfer = util::relay(relay, &throttles, &mut sock, &mut dst).await;
+        event.transfer(&transfer).log();
+        match (transfer.timeout, transfer.error) {
+            (Some(timeout), _) => Err(Socks4Error::Timeout(timeout)),
+            (None, Some(_)) => Err(Socks4Error::Transceiver),
+            (None, None) => Ok(()),
         }
     }
 }
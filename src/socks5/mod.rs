@@ -1,6 +1,14 @@
-use super::util;
+use super::util::{self, RelayOptions, Timeout};
+use crate::acl::{self, ConnectError, Rule};
+use crate::admission::{self, Admission, Permit, Refusal};
 use crate::config_loader::Socks5Config;
+use crate::domains::DomainFilter;
+use crate::listener::{self, Listener, Stream};
 use crate::logger;
+use crate::throttle::Limiter;
+use crate::users::Users;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
 
 use nom::{Err, IResult, Needed};
@@ -12,32 +20,126 @@ mod parser;
 enum Socks5Error {
     Handshake,
     InvalidAuth,
+    AuthFailed(String),
     InvalidRequest,
     TargetUnreachable,
+    TargetDenied,
+    DomainDenied,
     Transceiver,
+    Timeout(Timeout),
+}
+
+impl Socks5Error {
+    /// Kind of a failed SOCKS5 session
+    fn kind(&self) -> &'static str {
+        match self {
+            Socks5Error::Handshake => "handshake",
+            Socks5Error::InvalidAuth => "invalid_auth",
+            Socks5Error::AuthFailed(_) => "auth_failed",
+            Socks5Error::InvalidRequest => "invalid_request",
+            Socks5Error::TargetUnreachable => "target_unreachable",
+            Socks5Error::TargetDenied => "target_denied",
+            Socks5Error::DomainDenied => "domain_denied",
+            Socks5Error::Transceiver => "transceiver",
+            Socks5Error::Timeout(timeout) => timeout.kind(),
+        }
+    }
 }
 
 type Socks5Result<T> = Result<T, Socks5Error>;
 
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+/// How clients authenticate and which targets they may reach
+struct Policy {
+    target_acl: Arc<[Rule]>,
+    domains: DomainFilter,
+    /// users of username/password authentication, `None` if it is off
+    users: Option<Users>,
+    /// clients may skip authentication although there are users
+    allow_no_auth: bool,
+}
+
+impl Policy {
+    /// Authentication method for client offering `methods`
+    fn method(&self, methods: &[u8]) -> u8 {
+        if self.users.is_some() && methods.contains(&USER_PASS) {
+            USER_PASS
+        } else if (self.users.is_none() || self.allow_no_auth) && methods.contains(&NO_AUTH) {
+            NO_AUTH
+        } else {
+            NO_ACCEPTABLE_METHOD
+        }
+    }
+}
+
 pub struct Socks5 {
     name: String,
     config: Socks5Config,
+    limiter: Arc<Limiter>,
+    admission: Admission,
+    policy: Arc<Policy>,
 }
 
 impl Socks5 {
-    pub fn new(name: &String, config: &Socks5Config) -> Socks5 {
+    pub fn new(name: &str, config: &Socks5Config) -> Socks5 {
         Socks5 {
-            name: name.clone(),
+            name: name.to_string(),
             config: config.clone(),
+            limiter: Arc::new(Limiter::new(config.common.throttle.as_ref())),
+            admission: Admission::new(&config.common),
+            policy: Arc::new(Policy {
+                target_acl: config.common.target_rules(),
+                domains: DomainFilter::new(
+                    config.domain_blocklist.as_deref(),
+                    config.domain_allowlist.as_deref(),
+                ),
+                users: Users::new(config.users.as_ref(), config.htpasswd.as_deref()),
+                allow_no_auth: config.allow_no_auth.unwrap_or(false),
+            }),
         }
     }
-    pub async fn serve(&self) {
-        let listener = util::bind_listener(self.config.port).await;
-        loop {
-            let (sock, _addr) = listener.accept().await.unwrap();
+    pub async fn serve(&self, listeners: Vec<Listener>) {
+        listener::accept_all(("socks5", &self.name), listeners, |sock, addr| {
             let name_clone = self.name.clone();
-            tokio::spawn(async move { Self::socks5_parser(name_clone, sock).await.ok() });
-        }
+            let relay = self.config.common.relay_options();
+            let limiter = self.limiter.clone();
+            let admitted = match self.admission.admit(addr.ip()) {
+                Err(Refusal::ClientDenied) => {
+                    let reply = [0x5, NO_ACCEPTABLE_METHOD];
+                    let refusal = Refusal::ClientDenied;
+                    admission::refuse(("socks5", &self.name), sock, &addr, refusal, &reply);
+                    return None;
+                }
+                admitted => admitted,
+            };
+            let policy = self.policy.clone();
+            Some(async move {
+                let permit = match admitted {
+                    Ok(permit) => permit,
+                    Err(refusal) => {
+                        admission::log_refused(("socks5", &name_clone), &addr, refusal);
+                        Self::fail_request(sock, relay.handshake_timeout).await;
+                        return;
+                    }
+                };
+                let session =
+                    Self::socks5_parser(&name_clone, sock, relay, &limiter, permit, &policy);
+                if let Err(e) = session.await {
+                    let mut event = logger::warn("session failed")
+                        .engine("socks5", &name_clone)
+                        .client(addr)
+                        .error(e.kind());
+                    if let Socks5Error::AuthFailed(user) = &e {
+                        event = event.user(user);
+                    }
+                    event.log();
+                }
+            })
+        })
+        .await;
     }
 
     async fn parser_read<R, T, P>(stream: &mut R, parser: P) -> Option<T>
@@ -64,38 +166,104 @@ impl Socks5 {
         }
     }
 
-    async fn socks5_parser(name: String, mut sock: TcpStream) -> Socks5Result<()> {
+    /// Answer the greeting of an over-limit client with no authentication
+    /// and its request with general failure (`0x01`) within `deadline`
+    async fn fail_request(mut sock: Stream, deadline: Duration) {
         use tokio::io::AsyncWriteExt;
-        sock.set_nodelay(true).ok();
-        let auth_requeest = Self::parser_read(&mut sock, parser::parse_auth)
+        let exchange = async {
+            Self::parser_read(&mut sock, parser::parse_auth).await?;
+            sock.write_all(&[0x5, NO_AUTH]).await.ok()?;
+            Self::parser_read(&mut sock, parser::parse_request).await?;
+            sock.write_all(&[0x5, 0x1, 0x0, 0x1, 0, 0, 0, 0, 0, 0])
+                .await
+                .ok()
+        };
+        tokio::time::timeout(deadline, exchange).await.ok();
+    }
+
+    /// Username/password sub-negotiation (RFC 1929), returns the user
+    async fn authenticate(sock: &mut Stream, users: &Users) -> Socks5Result<String> {
+        use tokio::io::AsyncWriteExt;
+        let request = Self::parser_read(sock, parser::parse_user_pass)
             .await
             .ok_or(Socks5Error::Handshake)?;
-        if !auth_requeest.auths.contains(&0u8) {
-            //only support no auth
-            sock.write_all(&[0x5, 0xff]).await.ok();
-            return Err(Socks5Error::InvalidAuth);
-        }
-        //successful auth
-        sock.write_all(&[0x5, 0x0])
+        let valid = users.verify(&request.user, &request.password).await;
+        sock.write_all(&[0x1, if valid { 0x0 } else { 0x1 }])
             .await
             .or(Err(Socks5Error::Handshake))?;
-        let request = Self::parser_read(&mut sock, parser::parse_request)
+        match valid {
+            true => Ok(request.user),
+            false => Err(Socks5Error::AuthFailed(request.user)),
+        }
+    }
+
+    /// Negotiate authentication, read request, connect to its target if
+    /// `policy` allows and reply. Returns target connection, requested
+    /// target and authenticated user
+    async fn socks5_handshake(
+        sock: &mut Stream,
+        policy: &Policy,
+    ) -> Socks5Result<(TcpStream, String, Option<String>)> {
+        use tokio::io::AsyncWriteExt;
+        let auth_requeest = Self::parser_read(sock, parser::parse_auth)
             .await
-            .ok_or(Socks5Error::InvalidRequest)?;
-        sock.write_all(&[0x5, 0x0, 0x0])
+            .ok_or(Socks5Error::Handshake)?;
+        let method = policy.method(&auth_requeest.auths);
+        sock.write_all(&[0x5, method])
             .await
             .or(Err(Socks5Error::Handshake))?;
-        let mut dest = match request.addr {
-            RequestAddr::Ip(addr) => TcpStream::connect(SocketAddr::new(addr, request.port))
+        let user = match (method, &policy.users) {
+            (USER_PASS, Some(users)) => Some(Self::authenticate(sock, users).await?),
+            (NO_AUTH, _) => None,
+            _ => return Err(Socks5Error::InvalidAuth),
+        };
+        let request = Self::parser_read(sock, parser::parse_request)
+            .await
+            .ok_or(Socks5Error::InvalidRequest)?;
+        let allowed = match &request.addr {
+            RequestAddr::Ip(addr) => policy.domains.allows_address(*addr),
+            RequestAddr::Domain(domain) => policy.domains.allows(domain),
+        };
+        if !allowed {
+            // not allowed by ruleset
+            sock.write_all(&[0x5, 0x2, 0x0, 0x1, 0, 0, 0, 0, 0, 0])
                 .await
-                .or(Err(Socks5Error::TargetUnreachable))?,
+                .ok();
+            return Err(Socks5Error::DomainDenied);
+        }
+        let (connected, target) = match request.addr {
+            RequestAddr::Ip(addr) => {
+                let addr = SocketAddr::new(addr, request.port);
+                (
+                    acl::connect(&policy.target_acl, addr).await,
+                    addr.to_string(),
+                )
+            }
             RequestAddr::Domain(domain) => {
                 let domain = format!("{}:{}", domain, request.port);
-                TcpStream::connect(domain)
+                (
+                    acl::connect(&policy.target_acl, domain.as_str()).await,
+                    domain,
+                )
+            }
+        };
+        let dest = match connected {
+            Ok(dest) => dest,
+            Err(e) => {
+                // not allowed by ruleset or host unreachable
+                let (rep, error) = match e {
+                    ConnectError::Denied => (0x2, Socks5Error::TargetDenied),
+                    ConnectError::Unreachable => (0x4, Socks5Error::TargetUnreachable),
+                };
+                sock.write_all(&[0x5, rep, 0x0, 0x1, 0, 0, 0, 0, 0, 0])
                     .await
-                    .or(Err(Socks5Error::TargetUnreachable))?
+                    .ok();
+                return Err(error);
             }
         };
+        sock.write_all(&[0x5, 0x0, 0x0])
+            .await
+            .or(Err(Socks5Error::Handshake))?;
         let reply_addr = match dest.peer_addr().or(Err(Socks5Error::InvalidRequest))? {
             SocketAddr::V4(a) => {
                 let mut result = vec![1];
@@ -115,16 +283,41 @@ impl Socks5 {
         sock.write_all(&reply_addr)
             .await
             .or(Err(Socks5Error::Handshake))?;
-        logger::log(format!(
-            "socks5.{} {:?} -> {:?}",
-            name,
-            sock.peer_addr().or(Err(Socks5Error::Handshake))?,
-            dest.peer_addr().or(Err(Socks5Error::Handshake))?
-        ));
-        util::transceiver(&mut sock, &mut dest)
+        Ok((dest, target, user))
+    }
+
+    async fn socks5_parser(
+        name: &str,
+        mut sock: Stream,
+        relay: RelayOptions,
+        limiter: &Limiter,
+        _permit: Permit,
+        policy: &Policy,
+    ) -> Socks5Result<()> {
+        sock.set_nodelay(true).ok();
+        let handshake = Self::socks5_handshake(&mut sock, policy);
+        let (mut dest, target, user) = tokio::time::timeout(relay.handshake_timeout, handshake)
             .await
-            .or(Err(Socks5Error::Transceiver))?;
-        Ok(())
+            .or(Err(Socks5Error::Timeout(Timeout::Handshake)))??;
+        let client = sock.peer_addr().or(Err(Socks5Error::Handshake))?;
+        let throttles = limiter.session(client.ip(), user.as_deref());
+        let mut event = logger::access("session")
+            .engine("socks5", name)
+            .client(client)
+            .target(target)
+            .method("CONNECT")
+            .version("SOCKS5")
+            .peer(dest.peer_addr().or(Err(Socks5Error::Handshake))?);
+        if let Some(user) = user {
+            event = event.user(user);
+        }
+        let transfer = util::relay(relay, &throttles, &mut sock, &mut dest).await;
+        event.transfer(&transfer).log();
+        match (transfer.timeout, transfer.error) {
+            (Some(timeout), _) => Err(Socks5Error::Timeout(timeout)),
+            (None, Some(_)) => Err(Socks5Error::Transceiver),
+            (None, None) => Ok(()),
+        }
     }
 }
 
@@ -135,6 +328,12 @@ pub struct AuthRequest {
     pub auths: Vec<u8>,
 }
 
+struct UserPassRequest {
+    //VER 0x01
+    user: String,
+    password: String,
+}
+
 enum RequestAddr {
     Ip(IpAddr),
     Domain(String),
@@ -157,5 +356,17 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(auth_req.auths, [0, 1]);
+        let data = [1u8, 5, b'a', b'l', b'i', b'c', b'e', 3, b'p', b'w', b'd'];
+        let user_pass = Socks5::parser_read(&mut &data[..], parser::parse_user_pass)
+            .await
+            .unwrap();
+        assert_eq!(
+            (user_pass.user.as_str(), user_pass.password.as_str()),
+            ("alice", "pwd")
+        );
+        let data = [1u8, 5, b'a', b'l'];
+        assert!(Socks5::parser_read(&mut &data[..], parser::parse_user_pass)
+            .await
+            .is_none());
     }
 }
@@ -1,4 +1,4 @@
-use super::{AuthRequest, ConnectRequest, RequestAddr};
+use super::{AuthRequest, ConnectRequest, RequestAddr, UserPassRequest};
 use nom::{
     bytes::streaming::{tag, take},
     error::{make_error, ErrorKind},
@@ -18,6 +18,19 @@ pub fn parse_auth(input: &[u8]) -> IResult<&[u8], AuthRequest> {
     Ok((rest, request))
 }
 
+pub(super) fn parse_user_pass(input: &[u8]) -> IResult<&[u8], UserPassRequest> {
+    let (rest, _) = tag([1u8])(input)?;
+    let (rest, len) = be_u8(rest)?;
+    let (rest, user) = take(len)(rest)?;
+    let (rest, len) = be_u8(rest)?;
+    let (rest, password) = take(len)(rest)?;
+    let request = UserPassRequest {
+        user: String::from_utf8_lossy(user).into_owned(),
+        password: String::from_utf8_lossy(password).into_owned(),
+    };
+    Ok((rest, request))
+}
+
 pub(super) fn parse_request(input: &[u8]) -> IResult<&[u8], ConnectRequest> {
     let (rest, _) = tag([5u8])(input)?;
     let (rest, cmd) = be_u8(rest)?;
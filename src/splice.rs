@@ -0,0 +1,167 @@
+//! Relay between TCP sockets with splice(2) through a pipe, so data does
+//! not pass through userspace buffers.
+
+use crate::util::{self, HalfResult, RelayOptions, Side, Transfer};
+use std::io::{self, ErrorKind};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Bytes moved by one splice call, default capacity of a pipe
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        // SAFETY: pipe2 writes two descriptors into `fds`, which has room
+        // for exactly two
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just created by pipe2 and nothing
+        // else owns them, so `OwnedFd` closes each exactly once
+        unsafe {
+            Ok(Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+/// Move up to `len` bytes from `from` to `to`, one of them a pipe. Both
+/// descriptors must stay open for the call, callers pass descriptors of
+/// sockets and pipes they borrow.
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: splice only reads and writes kernel buffers of the two
+    // descriptors, which are open for the call; null offsets tell it to
+    // use the current file positions, so no memory of ours is touched
+    let result = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Move data from `from` to `to` through `pipe` until end of data, then
+/// shut down writing to `to`. Error is returned with the side it came
+/// from.
+async fn pump(
+    from: (&TcpStream, Side),
+    to: (&TcpStream, Side),
+    pipe: &Pipe,
+    counter: &AtomicU64,
+) -> HalfResult {
+    let (from, from_side) = from;
+    let (to, to_side) = to;
+    loop {
+        // pipe is empty here, so would block means no data in socket
+        let size = loop {
+            from.readable().await.map_err(|e| (from_side, e))?;
+            match from.try_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK_SIZE)
+            }) {
+                Ok(size) => break size,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err((from_side, e)),
+            }
+        };
+        if size == 0 {
+            socket2::SockRef::from(to).shutdown(Shutdown::Write).ok();
+            return Ok(());
+        }
+        let mut left = size;
+        while left > 0 {
+            to.writable().await.map_err(|e| (to_side, e))?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+            }) {
+                Ok(written) => {
+                    left -= written;
+                    counter.fetch_add(written as u64, Ordering::Relaxed);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err((to_side, e)),
+            }
+        }
+    }
+}
+
+/// Relay between client `src` and target `dst` like `util::transceiver`.
+/// Fails only if pipes can not be created.
+pub async fn transceiver(
+    src: &TcpStream,
+    dst: &TcpStream,
+    options: RelayOptions,
+) -> io::Result<Transfer> {
+    let up_pipe = Pipe::new()?;
+    let down_pipe = Pipe::new()?;
+    let upload = AtomicU64::new(0);
+    let download = AtomicU64::new(0);
+    let up = pump((src, Side::Client), (dst, Side::Target), &up_pipe, &upload);
+    let down = pump(
+        (dst, Side::Target),
+        (src, Side::Client),
+        &down_pipe,
+        &download,
+    );
+    Ok(util::drive(up, down, options, &upload, &download).await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Connected pair of loopback sockets
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connect, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn splice_half_close() {
+        let (mut client, src) = pair().await;
+        let (dst, mut target) = pair().await;
+        let relay = tokio::spawn(async move {
+            transceiver(&src, &dst, RelayOptions::default())
+                .await
+                .unwrap()
+        });
+        let request = vec![7u8; 200_000];
+        client.write_all(&request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, request);
+        target.write_all(b"done").await.unwrap();
+        drop(target);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"done");
+        let transfer = relay.await.unwrap();
+        assert_eq!((transfer.upload, transfer.download), (200_000, 4));
+        assert_eq!(transfer.closed_by, Side::Client);
+        assert!(transfer.error.is_none());
+    }
+}
@@ -1,30 +1,64 @@
-use super::util;
+use super::util::{self, Timeout};
+use crate::acl::{self, ConnectError};
+use crate::admission::{self, Admission};
+use crate::config_loader::TcpPmConfig;
+use crate::listener::{self, Listener};
 use crate::logger;
-use tokio::net::TcpStream;
+use crate::throttle::Limiter;
+use std::sync::Arc;
 
-pub async fn tcppm(name: String, src_port: u16, target: String) {
-    let listener = util::bind_listener(src_port).await;
-    loop {
-        let (mut src, _addr) = listener.accept().await.unwrap();
+pub async fn tcppm(name: String, config: TcpPmConfig, listeners: Vec<Listener>) {
+    let limiter = Arc::new(Limiter::new(config.common.throttle.as_ref()));
+    let admission = Admission::new(&config.common);
+    let target_acl = config.common.target_rules();
+    listener::accept_all(("tcppm", &name), listeners, |mut src, addr| {
         let name_clone = name.clone();
-        let target_clone = target.clone();
-        tokio::spawn(async move {
-            if let Ok(mut dst) = TcpStream::connect(&target_clone).await {
-                src.set_nodelay(true).ok();
-                dst.set_nodelay(true).ok();
-                logger::log(format!(
-                    "tcppm.{} {:?} -> {:?}",
-                    name_clone,
-                    src.peer_addr().unwrap(),
-                    dst.peer_addr().unwrap()
-                ));
-                util::transceiver(&mut src, &mut dst).await.ok();
-            } else {
-                logger::log(format!(
-                    "tcppm.{} failed to connect to {}",
-                    name_clone, target_clone
-                ));
+        let target_clone = config.target.clone();
+        let relay = config.common.relay_options();
+        let permit = match admission.admit(addr.ip()) {
+            Ok(permit) => permit,
+            Err(refusal) => {
+                admission::refuse(("tcppm", &name), src, &addr, refusal, &[]);
+                return None;
             }
-        });
-    }
+        };
+        let limiter = limiter.clone();
+        let target_acl = target_acl.clone();
+        Some(async move {
+            let _permit = permit;
+            let throttles = limiter.session(addr.ip(), None);
+            let connect = acl::connect(&target_acl, target_clone.as_str());
+            match tokio::time::timeout(relay.handshake_timeout, connect).await {
+                Ok(Ok(mut dst)) => {
+                    src.set_nodelay(true).ok();
+                    dst.set_nodelay(true).ok();
+                    let mut event = logger::access("session")
+                        .engine("tcppm", &name_clone)
+                        .client(addr)
+                        .target(&target_clone)
+                        .method("CONNECT")
+                        .version("TCP");
+                    if let Ok(peer) = dst.peer_addr() {
+                        event = event.peer(peer);
+                    }
+                    let transfer = util::relay(relay, &throttles, &mut src, &mut dst).await;
+                    event.transfer(&transfer).log();
+                }
+                result => {
+                    let error = match result {
+                        Err(_) => Timeout::Handshake.kind(),
+                        Ok(Err(ConnectError::Denied)) => "target_denied",
+                        _ => "target_unreachable",
+                    };
+                    logger::warn("failed to connect")
+                        .engine("tcppm", &name_clone)
+                        .client(addr)
+                        .target(&target_clone)
+                        .error(error)
+                        .log();
+                }
+            }
+        })
+    })
+    .await;
 }
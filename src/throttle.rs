@@ -0,0 +1,250 @@
+//! Bandwidth limits of relayed data
+//!
+//! Every limit is a token bucket filled with `rate` bytes per second.
+//! Sessions take tokens for the bytes they copy and wait when a bucket
+//! runs out, so sessions sharing a bucket share its rate.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bucket holds tokens for this part of a second, so a session idle for
+/// a while can not send much more than `rate` at once
+const BURST_DIVISOR: u64 = 10;
+
+/// Upload and download limits in bytes per second
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rates {
+    /// from client to target
+    pub upload: Option<u64>,
+    /// from target to client
+    pub download: Option<u64>,
+}
+
+/// `throttle` table of engine configuration
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ThrottleConfig {
+    /// shared by all sessions of the engine
+    pub engine: Option<Rates>,
+    /// shared by sessions from one client address
+    pub client: Option<Rates>,
+    /// shared by sessions of one authenticated user
+    pub user: Option<Rates>,
+}
+
+pub struct Bucket {
+    rate: u64,
+    burst: u64,
+    /// available tokens, negative when taken in advance
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    pub fn new(rate: u64) -> Bucket {
+        let burst = (rate / BURST_DIVISOR).max(1);
+        Bucket {
+            rate,
+            burst,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Take `n` tokens, return how long to wait until they are available
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated) = &mut *state;
+        let now = Instant::now();
+        let filled = *tokens + now.duration_since(*updated).as_secs_f64() * self.rate as f64;
+        *tokens = filled.min(self.burst as f64) - n as f64;
+        *updated = now;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Buckets limiting one direction of a session
+#[derive(Default, Clone)]
+pub struct Throttle {
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Throttle {
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Largest amount worth copying at once, so waits stay short
+    pub fn chunk(&self, size: usize) -> usize {
+        self.buckets
+            .iter()
+            .map(|b| b.burst as usize)
+            .fold(size, usize::min)
+            .max(1)
+    }
+
+    /// Account `n` copied bytes, waiting if any bucket is exhausted
+    pub async fn consume(&self, n: usize) {
+        let wait = self
+            .buckets
+            .iter()
+            .map(|b| b.take(n))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limits of one session
+#[derive(Default, Clone)]
+pub struct Throttles {
+    pub upload: Throttle,
+    pub download: Throttle,
+}
+
+impl Throttles {
+    pub fn is_empty(&self) -> bool {
+        self.upload.is_empty() && self.download.is_empty()
+    }
+
+    fn add(&mut self, buckets: &Pair) {
+        self.upload.buckets.extend(buckets.upload.iter().cloned());
+        self.download
+            .buckets
+            .extend(buckets.download.iter().cloned());
+    }
+}
+
+struct Pair {
+    upload: Option<Arc<Bucket>>,
+    download: Option<Arc<Bucket>>,
+}
+
+impl Pair {
+    fn new(rates: &Rates) -> Pair {
+        Pair {
+            upload: rates.upload.map(|r| Arc::new(Bucket::new(r))),
+            download: rates.download.map(|r| Arc::new(Bucket::new(r))),
+        }
+    }
+
+    /// No session holds the buckets
+    fn unused(&self) -> bool {
+        [&self.upload, &self.download]
+            .into_iter()
+            .flatten()
+            .all(|b| Arc::strong_count(b) == 1)
+    }
+}
+
+/// Buckets shared by sessions with the same key, kept while used
+struct Group<K> {
+    rates: Rates,
+    buckets: Mutex<HashMap<K, Pair>>,
+}
+
+impl<K: Hash + Eq> Group<K> {
+    fn new(rates: Rates) -> Group<K> {
+        Group {
+            rates,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn add_to(&self, key: K, throttles: &mut Throttles) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&key) {
+            buckets.retain(|_, pair| !pair.unused());
+        }
+        throttles.add(buckets.entry(key).or_insert_with(|| Pair::new(&self.rates)));
+    }
+}
+
+/// Limits of an engine, gives every session its buckets
+pub struct Limiter {
+    engine: Option<Pair>,
+    clients: Option<Group<IpAddr>>,
+    users: Option<Group<String>>,
+}
+
+impl Limiter {
+    pub fn new(config: Option<&ThrottleConfig>) -> Limiter {
+        let config = config.copied().unwrap_or_default();
+        Limiter {
+            engine: config.engine.as_ref().map(Pair::new),
+            clients: config.client.map(Group::new),
+            users: config.user.map(Group::new),
+        }
+    }
+
+    /// Buckets of session from `client` authenticated as `user`
+    pub fn session(&self, client: Option<IpAddr>, user: Option<&str>) -> Throttles {
+        let mut throttles = Throttles::default();
+        if let Some(engine) = &self.engine {
+            throttles.add(engine);
+        }
+        if let (Some(clients), Some(client)) = (&self.clients, client) {
+            clients.add_to(client, &mut throttles);
+        }
+        if let (Some(users), Some(user)) = (&self.users, user) {
+            users.add_to(user.to_string(), &mut throttles);
+        }
+        throttles
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn bucket_waits() {
+        let bucket = Bucket::new(1000);
+        assert_eq!(bucket.take(100), Duration::ZERO);
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    }
+    #[test]
+    fn shared_buckets() {
+        let rates = Rates {
+            upload: Some(1000),
+            download: None,
+        };
+        let limiter = Limiter::new(Some(&ThrottleConfig {
+            engine: None,
+            client: Some(rates),
+            user: Some(rates),
+        }));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = limiter.session(Some(a), Some("bob"));
+        assert_eq!(
+            (first.upload.buckets.len(), first.download.buckets.len()),
+            (2, 0)
+        );
+        let second = limiter.session(Some(a), None);
+        assert!(Arc::ptr_eq(
+            &first.upload.buckets[0],
+            &second.upload.buckets[0]
+        ));
+        drop((first, second));
+        // buckets of finished sessions are dropped with next new client
+        limiter.session(Some("10.0.0.2".parse().unwrap()), None);
+        assert_eq!(
+            limiter
+                .clients
+                .as_ref()
+                .unwrap()
+                .buckets
+                .lock()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Users of proxy authentication
+//!
+//! Users are listed in configuration and/or in an htpasswd file, which
+//! is read again when it changes. A password is stored in plain text or
+//! hashed as htpasswd does: bcrypt (`$2y$`), SHA-256 or SHA-512 crypt
+//! (`$5$`, `$6$`) and `{SHA}`. Entries with other `$id$` or `{NAME}`
+//! hashes are left out with a warning. Configuration entries take
+//! precedence over the file.
+
+use crate::logger;
+use crate::watched_file::{Content, WatchedFile};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Content of htpasswd file: `user:password` lines
+#[derive(Default)]
+struct Htpasswd {
+    entries: HashMap<String, String>,
+}
+
+impl Content for Htpasswd {
+    const KIND: &'static str = "htpasswd file";
+
+    fn parse(text: &str) -> (Htpasswd, Vec<String>) {
+        let mut entries = HashMap::new();
+        let mut errors = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) if !user.is_empty() => match unsupported(password) {
+                    Some(scheme) => errors.push(format!(
+                        "line {}: user {}: unsupported hash {}",
+                        n + 1,
+                        user,
+                        scheme
+                    )),
+                    None => {
+                        entries.insert(user.to_string(), password.to_string());
+                    }
+                },
+                _ => errors.push(format!("line {}: not in user:password format", n + 1)),
+            }
+        }
+        (Htpasswd { entries }, errors)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Compare without stopping at the first difference
+pub fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+const CRYPT_SCHEMES: [&str; 5] = ["$2a$", "$2b$", "$2y$", "$5$", "$6$"];
+
+/// Hash scheme prefix of `stored`, `$id$` or `{NAME}`, `None` for plain
+/// text
+fn hash_scheme(stored: &str) -> Option<&str> {
+    let end = match stored.as_bytes().first()? {
+        b'$' => stored[1..].find('$')? + 2,
+        b'{' => stored.find('}')? + 1,
+        _ => return None,
+    };
+    Some(&stored[..end])
+}
+
+fn is_crypt_hash(stored: &str) -> bool {
+    hash_scheme(stored).is_some_and(|scheme| CRYPT_SCHEMES.contains(&scheme))
+}
+
+/// Hash scheme of `stored` if it is one that can not be checked
+fn unsupported(stored: &str) -> Option<&str> {
+    hash_scheme(stored).filter(|&scheme| scheme != "{SHA}" && !CRYPT_SCHEMES.contains(&scheme))
+}
+
+/// Check `password` against `stored` password or hash. Crypt hashes are
+/// slow by design and should be checked off the async runtime.
+fn verify_stored(stored: &str, password: &str) -> bool {
+    if let Some(hash) = stored.strip_prefix("{SHA}") {
+        let digest = BASE64_STANDARD.encode(Sha1::digest(password.as_bytes()));
+        same(digest.as_bytes(), hash.as_bytes())
+    } else if is_crypt_hash(stored) {
+        pwhash::unix::verify(password, stored)
+    } else {
+        same(stored.as_bytes(), password.as_bytes())
+    }
+}
+
+/// Users file content credentials were checked against
+pub struct Snapshot(Option<Arc<Htpasswd>>);
+
+pub struct Users {
+    listed: HashMap<String, String>,
+    file: Option<WatchedFile<Htpasswd>>,
+}
+
+impl Users {
+    /// Users of an engine, `None` if it has none and authentication is off
+    pub fn new(listed: Option<&BTreeMap<String, String>>, file: Option<&Path>) -> Option<Users> {
+        if listed.is_none() && file.is_none() {
+            return None;
+        }
+        let mut listed: HashMap<_, _> = listed.cloned().unwrap_or_default().into_iter().collect();
+        listed.retain(|user, stored| match unsupported(stored) {
+            Some(scheme) => {
+                logger::warn(format!("users: user {}: unsupported hash {}", user, scheme)).log();
+                false
+            }
+            None => true,
+        });
+        Some(Users {
+            listed,
+            file: file.map(WatchedFile::new),
+        })
+    }
+
+    /// Current content of the users file
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot(self.file.as_ref().map(WatchedFile::current))
+    }
+
+    /// Whether the users file was not read again since `snapshot`
+    pub fn is_current(&self, snapshot: &Snapshot) -> bool {
+        match (&snapshot.0, &self.file) {
+            (Some(old), Some(file)) => Arc::ptr_eq(old, &file.current()),
+            _ => true,
+        }
+    }
+
+    /// Stored password or hash of `user`
+    fn stored(&self, user: &str) -> Option<String> {
+        match self.listed.get(user) {
+            Some(stored) => Some(stored.clone()),
+            None => self.file.as_ref()?.current().entries.get(user).cloned(),
+        }
+    }
+
+    /// Plain text password of `user`, needed by schemes that never see
+    /// the password itself
+    pub fn password(&self, user: &str) -> Option<String> {
+        self.stored(user)
+            .filter(|stored| hash_scheme(stored).is_none())
+    }
+
+    pub async fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(stored) = self.stored(user) else {
+            return false;
+        };
+        if !is_crypt_hash(&stored) {
+            return verify_stored(&stored, password);
+        }
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || verify_stored(&stored, &password))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn stored_passwords() {
+        let listed = BTreeMap::from([
+            ("plain".to_string(), "secret".to_string()),
+            (
+                "sha".to_string(),
+                "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=".to_string(),
+            ),
+            (
+                "bcrypt".to_string(),
+                "$2y$05$fDiNzSfRdmVh2aLf2S.CoOQtzWud4N/78ZJphuLLqT29QbobXIvEi".to_string(),
+            ),
+            (
+                "sha512".to_string(),
+                concat!(
+                    "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5",
+                    "knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1"
+                )
+                .to_string(),
+            ),
+        ]);
+        let users = Users::new(Some(&listed), None).unwrap();
+        for user in ["plain", "sha", "bcrypt", "sha512"] {
+            assert!(users.verify(user, "secret").await, "{}", user);
+            assert!(!users.verify(user, "Secret").await, "{}", user);
+        }
+        assert!(!users.verify("nobody", "secret").await);
+        assert_eq!(users.password("plain").as_deref(), Some("secret"));
+        assert_eq!(users.password("sha"), None);
+        assert_eq!(users.password("bcrypt"), None);
+        let listed = BTreeMap::from([("md5".to_string(), "$1$salt$hash".to_string())]);
+        let users = Users::new(Some(&listed), None).unwrap();
+        assert!(!users.verify("md5", "$1$salt$hash").await);
+        assert_eq!(users.password("md5"), None);
+        assert!(Users::new(None, None).is_none());
+    }
+    #[test]
+    fn file_snapshots() {
+        use std::fs;
+        use std::time::{Duration, SystemTime};
+        let path = std::env::temp_dir().join(format!("htpasswd-{}", std::process::id()));
+        fs::write(&path, "alice:secret\n").unwrap();
+        let users = Users::new(None, Some(&path)).unwrap();
+        let snapshot = users.snapshot();
+        assert!(users.is_current(&snapshot));
+        fs::write(&path, "alice:changed\n").unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(5);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        users.file.as_ref().unwrap().check();
+        assert!(!users.is_current(&snapshot));
+        assert!(users.is_current(&users.snapshot()));
+        fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn htpasswd_lines() {
+        let (file, errors) = Htpasswd::parse(concat!(
+            "# users\nalice:$6$salt$hash\n\nbroken\n:nouser\n",
+            "bob:$apr1$salt$hash\ncarol:{SSHA}hash\ndave:$ecret\n"
+        ));
+        assert_eq!(file.len(), 2);
+        assert_eq!(
+            errors,
+            [
+                "line 4: not in user:password format",
+                "line 5: not in user:password format",
+                "line 6: user bob: unsupported hash $apr1$",
+                "line 7: user carol: unsupported hash {SSHA}"
+            ]
+        );
+        assert_eq!(file.entries["dave"], "$ecret");
+    }
+}
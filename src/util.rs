@@ -1,51 +1,433 @@
-use std::net::SocketAddr;
+use crate::buffer_pool;
+use crate::listener::Stream;
+use crate::throttle::{Throttle, Throttles};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind, ReadBuf};
+use tokio::net::TcpStream;
 
-use socket2::{Domain, Protocol, Socket, Type};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Result};
-use tokio::net::TcpListener;
+/// How relayed sessions move data between sockets
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    /// read into a buffer and write it out
+    #[default]
+    Copy,
+    /// splice(2) through a pipe, only between TCP sockets on Linux
+    Splice,
+}
+
+/// Side of a relayed session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Client,
+    Target,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Side::Client => "client",
+            Side::Target => "target",
+        })
+    }
+}
+
+/// Deadline that ended a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    /// session was not established in time
+    Handshake,
+    /// no data moved for too long
+    Idle,
+    /// session lasted longer than allowed
+    Lifetime,
+}
+
+impl Timeout {
+    /// Kind of a session ended by this deadline
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Timeout::Handshake => "handshake_timeout",
+            Timeout::Idle => "idle_timeout",
+            Timeout::Lifetime => "lifetime_exceeded",
+        }
+    }
+}
+
+/// Outcome of a relayed session
+#[derive(Debug)]
+pub struct Transfer {
+    /// bytes sent from client to target
+    pub upload: u64,
+    /// bytes sent from target to client
+    pub download: u64,
+    pub duration: Duration,
+    /// side that closed its connection or failed first
+    pub closed_by: Side,
+    pub error: Option<Error>,
+    /// deadline that ended the session
+    pub timeout: Option<Timeout>,
+}
+
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a half-closed session may stay without any data
+pub const HALF_CLOSED_TIMEOUT: Duration = Duration::from_secs(60);
 
-pub async fn transceiver<S, D>(src: &mut S, dst: &mut D) -> Result<()>
+/// Session settings of an engine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayOptions {
+    pub mode: RelayMode,
+    /// size of buffers taken from `buffer_pool`
+    pub buffer_size: usize,
+    /// time from accept until the session is established
+    pub handshake_timeout: Duration,
+    /// time without data in either direction
+    pub idle_timeout: Duration,
+    /// time the relay may last at all
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for RelayOptions {
+    fn default() -> RelayOptions {
+        RelayOptions {
+            mode: RelayMode::Copy,
+            buffer_size: buffer_pool::DEFAULT_BUFFER_SIZE,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_lifetime: None,
+        }
+    }
+}
+
+/// Result of one direction of a session, error comes with the side
+/// that caused it
+pub type HalfResult = Result<(), (Side, Error)>;
+
+/// Read into `buf` only if data is available now
+async fn read_ready<R: AsyncRead + Unpin>(
+    from: &mut R,
+    buf: &mut [u8],
+) -> Option<io::Result<usize>> {
+    std::future::poll_fn(|cx| {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *from).poll_read(cx, &mut buf) {
+            Poll::Ready(result) => Poll::Ready(Some(result.map(|_| buf.filled().len()))),
+            Poll::Pending => Poll::Ready(None),
+        }
+    })
+    .await
+}
+
+/// Copy one direction from `from` to `to` until end of data, then shut
+/// down writing to `to`.
+///
+/// While waiting for data only one byte is held, a pooled buffer is taken
+/// when data arrives and given back when no more is ready. Data is
+/// written out after `throttle` lets it through.
+async fn copy_half<R, W>(
+    from: (&mut R, Side),
+    to: (&mut W, Side),
+    buffer_size: usize,
+    throttle: &Throttle,
+    counter: &AtomicU64,
+) -> HalfResult
 where
-    S: AsyncRead + AsyncWrite + Unpin,
-    D: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
 {
-    let mut src_buf = [0u8; 2000];
-    let mut dst_buf = [0u8; 2000];
+    let (from, from_side) = from;
+    let (to, to_side) = to;
     loop {
-        tokio::select! {
-            Ok(size) = src.read(&mut src_buf) => {
-                if size == 0 {return Ok(())};
-                dst.write_all(&src_buf[..size]).await?;
-            }
-            Ok(size) = dst.read(&mut dst_buf) => {
-                if size == 0 {return Ok(())};
-                src.write_all(&dst_buf[..size]).await?;
+        let mut first = [0u8; 1];
+        if from.read(&mut first).await.map_err(|e| (from_side, e))? == 0 {
+            to.shutdown().await.ok();
+            return Ok(());
+        }
+        let mut buf = buffer_pool::get(buffer_size);
+        let limit = throttle.chunk(buf.len());
+        buf[0] = first[0];
+        let mut len = 1;
+        loop {
+            let mut finished = false;
+            while len < limit {
+                match read_ready(from, &mut buf[len..limit]).await {
+                    Some(Ok(0)) => {
+                        finished = true;
+                        break;
+                    }
+                    Some(Ok(size)) => len += size,
+                    Some(Err(e)) => return Err((from_side, e)),
+                    None => break,
+                }
             }
-            else => {
+            throttle.consume(len).await;
+            to.write_all(&buf[..len]).await.map_err(|e| (to_side, e))?;
+            counter.fetch_add(len as u64, Ordering::Relaxed);
+            if finished {
+                to.shutdown().await.ok();
                 return Ok(());
             }
+            if len < limit {
+                // no more data ready, give buffer back while waiting
+                break;
+            }
+            len = 0;
         }
     }
 }
 
-pub async fn bind_listener(port: u16) -> TcpListener {
-    let addr: SocketAddr = format!("[::]:{}", port).parse().unwrap();
-    let std_listener = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)).unwrap();
-    std_listener.set_only_v6(false).unwrap();
-    std_listener.set_tcp_nodelay(true).unwrap();
-    std_listener.set_reuse_address(true).unwrap();
-    std_listener.set_nonblocking(true).unwrap();
-    std_listener.bind(&addr.into()).unwrap();
-    std_listener.listen(1024).unwrap();
-    tokio::net::TcpListener::from_std(std_listener.into()).unwrap()
+/// Run both directions of a session until they finish, one of them
+/// fails or a deadline of `options` passes. Half-closed session is idle
+/// after `HALF_CLOSED_TIMEOUT` at most.
+pub async fn drive<U, D>(
+    up: U,
+    down: D,
+    options: RelayOptions,
+    upload: &AtomicU64,
+    download: &AtomicU64,
+) -> Transfer
+where
+    U: Future<Output = HalfResult>,
+    D: Future<Output = HalfResult>,
+{
+    let started = Instant::now();
+    tokio::pin!(up, down);
+    let mut uploading = true;
+    let mut downloading = true;
+    let mut closed_by = None;
+    let mut error = None;
+    let mut timeout = None;
+    let lifetime = async {
+        match options.max_lifetime {
+            Some(lifetime) => tokio::time::sleep(lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(lifetime);
+    // activity is sampled a few times per idle period
+    let mut idle_limit = options.idle_timeout;
+    let mut check = tokio::time::interval(idle_limit / 4);
+    check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut moved = 0;
+    let mut active = Instant::now();
+    let total = || upload.load(Ordering::Relaxed) + download.load(Ordering::Relaxed);
+    while uploading || downloading {
+        let (result, side) = tokio::select! {
+            result = &mut up, if uploading => {
+                uploading = false;
+                (result, Side::Client)
+            }
+            result = &mut down, if downloading => {
+                downloading = false;
+                (result, Side::Target)
+            }
+            _ = check.tick() => {
+                if total() != moved {
+                    moved = total();
+                    active = Instant::now();
+                } else if active.elapsed() >= idle_limit {
+                    timeout = Some(Timeout::Idle);
+                    break;
+                }
+                continue;
+            }
+            _ = &mut lifetime => {
+                timeout = Some(Timeout::Lifetime);
+                break;
+            }
+        };
+        match result {
+            Ok(()) => {
+                closed_by.get_or_insert(side);
+                if idle_limit > HALF_CLOSED_TIMEOUT {
+                    idle_limit = HALF_CLOSED_TIMEOUT;
+                    check = tokio::time::interval(idle_limit / 4);
+                }
+                active = Instant::now();
+            }
+            Err((side, e)) => {
+                closed_by.get_or_insert(side);
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    if let Some(timeout) = timeout {
+        error = Some(Error::new(ErrorKind::TimedOut, timeout.kind()));
+    }
+    Transfer {
+        upload: upload.load(Ordering::Relaxed),
+        download: download.load(Ordering::Relaxed),
+        duration: started.elapsed(),
+        closed_by: closed_by.unwrap_or(Side::Client),
+        error,
+        timeout,
+    }
+}
+
+/// Relay data between client `src` and target `dst`.
+///
+/// Both directions are copied independently. When one side finishes
+/// sending, end of data is passed to the other side with `shutdown` and
+/// the opposite direction is copied until it finishes too.
+pub async fn transceiver<S, D>(
+    src: &mut S,
+    dst: &mut D,
+    options: RelayOptions,
+    throttles: &Throttles,
+) -> Transfer
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut src_read, mut src_write) = tokio::io::split(src);
+    let (mut dst_read, mut dst_write) = tokio::io::split(dst);
+    let upload = AtomicU64::new(0);
+    let download = AtomicU64::new(0);
+    let up = copy_half(
+        (&mut src_read, Side::Client),
+        (&mut dst_write, Side::Target),
+        options.buffer_size,
+        &throttles.upload,
+        &upload,
+    );
+    let down = copy_half(
+        (&mut dst_read, Side::Target),
+        (&mut src_write, Side::Client),
+        options.buffer_size,
+        &throttles.download,
+        &download,
+    );
+    drive(up, down, options, &upload, &download).await
+}
+
+/// Relay with splice(2) if `options` ask for it and both sides are TCP
+/// sockets. `None` means data must be copied.
+pub async fn try_splice(options: RelayOptions, src: &Stream, dst: &TcpStream) -> Option<Transfer> {
+    #[cfg(target_os = "linux")]
+    if let (RelayMode::Splice, Stream::Tcp(src)) = (options.mode, src) {
+        match crate::splice::transceiver(src, dst, options).await {
+            Ok(transfer) => return Some(transfer),
+            Err(e) => crate::logger::warn(format!("splice failed, copying instead: {}", e)).log(),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (options, src, dst);
+    None
+}
+
+/// Relay between client `src` and target `dst` with `options`, falling
+/// back to `transceiver`. Throttled sessions are always copied.
+pub async fn relay(
+    options: RelayOptions,
+    throttles: &Throttles,
+    src: &mut Stream,
+    dst: &mut TcpStream,
+) -> Transfer {
+    if throttles.is_empty() {
+        if let Some(transfer) = try_splice(options, src, dst).await {
+            return transfer;
+        }
+    }
+    transceiver(src, dst, options, throttles).await
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    fn options() -> RelayOptions {
+        RelayOptions {
+            buffer_size: 16,
+            ..RelayOptions::default()
+        }
+    }
     #[test]
     #[should_panic]
     fn invalid_resolve() {
         use std::net::ToSocketAddrs as _;
         "127.0.0.1:80:70".to_socket_addrs().unwrap();
     }
+    #[tokio::test]
+    async fn transfer_counts() {
+        let (mut client, mut client_end) = tokio::io::duplex(64);
+        let (mut target, mut target_end) = tokio::io::duplex(64);
+        let relay = tokio::spawn(async move {
+            transceiver(
+                &mut client_end,
+                &mut target_end,
+                options(),
+                &Throttles::default(),
+            )
+            .await
+        });
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        target.read_exact(&mut buf).await.unwrap();
+        target.write_all(b"hi").await.unwrap();
+        client.read_exact(&mut buf[..2]).await.unwrap();
+        drop(target);
+        // end of data from target is passed to client
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        drop(client);
+        let transfer = relay.await.unwrap();
+        assert_eq!((transfer.upload, transfer.download), (5, 2));
+        assert_eq!(transfer.closed_by, Side::Target);
+        assert!(transfer.error.is_none());
+    }
+    #[tokio::test]
+    async fn half_close() {
+        let (mut client, mut client_end) = tokio::io::duplex(64);
+        let (mut target, mut target_end) = tokio::io::duplex(64);
+        let relay = tokio::spawn(async move {
+            transceiver(
+                &mut client_end,
+                &mut target_end,
+                options(),
+                &Throttles::default(),
+            )
+            .await
+        });
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        target.write_all(b"response").await.unwrap();
+        drop(target);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        let transfer = relay.await.unwrap();
+        assert_eq!(transfer.closed_by, Side::Client);
+        assert_eq!((transfer.upload, transfer.download), (7, 8));
+    }
+    #[tokio::test]
+    async fn deadlines() {
+        let (_client, mut client_end) = tokio::io::duplex(64);
+        let (_target, mut target_end) = tokio::io::duplex(64);
+        let idle = RelayOptions {
+            idle_timeout: Duration::from_millis(200),
+            ..options()
+        };
+        let none = Throttles::default();
+        let transfer = transceiver(&mut client_end, &mut target_end, idle, &none).await;
+        assert_eq!(transfer.timeout, Some(Timeout::Idle));
+        assert!(transfer.duration >= Duration::from_millis(200));
+        let short = RelayOptions {
+            max_lifetime: Some(Duration::from_millis(100)),
+            ..options()
+        };
+        let transfer = transceiver(&mut client_end, &mut target_end, short, &none).await;
+        assert_eq!(transfer.timeout, Some(Timeout::Lifetime));
+        assert_eq!(transfer.error.unwrap().kind(), ErrorKind::TimedOut);
+    }
 }
@@ -0,0 +1,127 @@
+//! Files read again when their modification time changes
+
+use crate::logger;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+use tokio::task;
+use tokio::time::MissedTickBehavior;
+
+/// How often files are checked for changes
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a watched file holds
+pub trait Content: Default + Send + Sync + 'static {
+    /// name of the file kind used in log
+    const KIND: &'static str;
+    /// Parse file text, invalid lines are reported by number
+    fn parse(text: &str) -> (Self, Vec<String>);
+    /// Number of entries, for log
+    fn len(&self) -> usize;
+}
+
+/// What the last reads found
+#[derive(Default)]
+struct ReadState {
+    /// modification time of the file in use
+    modified: Option<SystemTime>,
+    /// error of the last read if it failed, logged once until it changes
+    error: Option<String>,
+}
+
+/// File state shared with the watcher task
+struct Shared<T> {
+    path: PathBuf,
+    content: RwLock<Arc<T>>,
+    state: Mutex<ReadState>,
+}
+
+/// File content kept current by a background task, which ends when the
+/// file is dropped
+pub struct WatchedFile<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Content> WatchedFile<T> {
+    /// Read the file and, inside a runtime, start checking it for changes
+    pub fn new(path: &Path) -> WatchedFile<T> {
+        let shared = Arc::new(Shared {
+            path: path.to_path_buf(),
+            content: RwLock::default(),
+            state: Mutex::default(),
+        });
+        shared.reload();
+        if let Ok(runtime) = Handle::try_current() {
+            runtime.spawn(watch(Arc::downgrade(&shared)));
+        }
+        WatchedFile { shared }
+    }
+
+    /// Current content, only a reference count is taken
+    pub fn current(&self) -> Arc<T> {
+        self.shared.content.read().unwrap().clone()
+    }
+
+    /// Check for changes now instead of waiting for the watcher
+    #[cfg(test)]
+    pub fn check(&self) {
+        self.shared.reload();
+    }
+}
+
+/// Check the file every [`CHECK_INTERVAL`] until it is dropped, reading
+/// off the runtime threads
+async fn watch<T: Content>(shared: Weak<Shared<T>>) {
+    let mut check = tokio::time::interval(CHECK_INTERVAL);
+    check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    check.tick().await;
+    loop {
+        check.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if task::spawn_blocking(move || shared.reload()).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl<T: Content> Shared<T> {
+    fn modified(&self) -> io::Result<SystemTime> {
+        fs::metadata(&self.path)?.modified()
+    }
+
+    /// Read the file if it changed since last read. A file that can not
+    /// be read leaves the previous content in use.
+    fn reload(&self) {
+        let mut state = self.state.lock().unwrap();
+        let modified = self.modified().ok();
+        if modified.is_some() && modified == state.modified {
+            return;
+        }
+        let path = self.path.display();
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) => {
+                let error = e.to_string();
+                if state.error.as_ref() != Some(&error) {
+                    logger::error(format!("can not read {} {}: {}", T::KIND, path, error)).log();
+                    state.error = Some(error);
+                }
+                return;
+            }
+        };
+        let (content, errors) = T::parse(&text);
+        for e in errors {
+            logger::warn(format!("{} {}: {}", T::KIND, path, e)).log();
+        }
+        let message = format!("{} {} loaded: {} entries", T::KIND, path, content.len());
+        logger::info(message).log();
+        *self.content.write().unwrap() = Arc::new(content);
+        state.modified = modified;
+        state.error = None;
+    }
+}